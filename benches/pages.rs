@@ -0,0 +1,54 @@
+#![feature(test)]
+
+extern crate test;
+
+use io_backplane::Buffer;
+
+use std::sync::mpsc;
+use std::thread;
+use test::{black_box, Bencher};
+
+const THREADS: usize = 8;
+const ROUNDS: usize = 1000;
+
+#[bench]
+fn page_in_out(b: &mut Bencher) {
+    b.iter(|| black_box(Buffer::with_pages(1).unwrap()));
+}
+
+// Every thread taking and dropping pages at once, as a busy executor does.
+#[bench]
+fn page_in_out_threads(b: &mut Bencher) {
+    b.iter(|| {
+        let threads: Vec<_> = (0..THREADS).map(|_| thread::spawn(|| {
+            for _ in 0..ROUNDS {
+                black_box(Buffer::with_pages(1).unwrap());
+            }
+        })).collect();
+        for thread in threads { thread.join().unwrap(); }
+    });
+}
+
+// Pages taken on one thread and dropped on another, as when a buffer is
+// handed between tasks.
+#[bench]
+fn page_across_threads(b: &mut Bencher) {
+    b.iter(|| {
+        let threads: Vec<_> = (0..THREADS / 2).map(|_| {
+            let (tx, rx) = mpsc::sync_channel(64);
+            let taker = thread::spawn(move || {
+                for _ in 0..ROUNDS {
+                    tx.send(Buffer::with_pages(1).unwrap()).unwrap();
+                }
+            });
+            let dropper = thread::spawn(move || {
+                for buffer in rx { drop(buffer); }
+            });
+            (taker, dropper)
+        }).collect();
+        for (taker, dropper) in threads {
+            taker.join().unwrap();
+            dropper.join().unwrap();
+        }
+    });
+}
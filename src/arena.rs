@@ -0,0 +1,182 @@
+// Pages are carved out of large mappings, so we make one mmap (and use up
+// one VMA) per arena rather than one per page. Each arena keeps its own
+// free pages by index; they go back to it when their Buffer is dropped.
+
+use concurrent_queue::ConcurrentQueue;
+#[cfg(target_os = "linux")]
+use once_cell::sync::Lazy;
+use crate::buffer::{config, HugePages};
+use crate::mmap::MmapMut;
+use crate::numa::NumaNode;
+use crate::pool::Pool;
+
+use std::io::Error;
+#[cfg(not(target_os = "linux"))]
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+pub(crate) struct Arena {
+    base: *mut u8,
+    pages: usize,
+    // Taken out and dropped, unmapping it, when the arena is retired.
+    mmap: Mutex<Option<MmapMut>>,
+    free: ConcurrentQueue<u32>,
+    // Whether the arena is in its pool's available queue, or held by
+    // someone who will put it back there, so it's only ever in there once.
+    listed: AtomicBool,
+    pub(crate) pool: Weak<Pool>,
+    // The pool's slot in threads' caches, if it has one.
+    pub(crate) slot: Option<usize>,
+}
+
+// The mapping is only ever reached through the pages handed out.
+unsafe impl Send for Arena {}
+unsafe impl Sync for Arena {}
+
+// A page's worth of an arena.
+pub(crate) struct Block {
+    pub(crate) ptr: *mut u8,
+    pub(crate) arena: Arc<Arena>,
+    index: u32,
+}
+
+unsafe impl Send for Block {}
+
+impl Arena {
+    // No more than `max_pages` pages, though with huge pages the mapping
+    // may run on past them.
+    pub(crate) fn new(
+        pool: Weak<Pool>, node: Option<NumaNode>, slot: Option<usize>, max_pages: usize
+    ) -> Result<Arc<Arena>, Error> {
+        let config = config();
+        let size = config.arena_size.min(max_pages.saturating_mul(config.page_size));
+        // Memory for a node has to be bound before it's faulted in.
+        let populate = node.is_none();
+        let mut mmap = match config.huge_pages {
+            HugePages::Never => MmapMut::anon(size, populate)?,
+            #[cfg(target_os = "linux")]
+            HugePages::Transparent => {
+                let mut mmap = MmapMut::anon(round_up(size, huge_page_size()), false)?;
+                // Not fatal: we just end up with normal pages.
+                #[allow(unused_must_use)]
+                { mmap.advise_huge(); }
+                mmap
+            }
+            #[cfg(target_os = "linux")]
+            HugePages::HugeTlb => MmapMut::anon_hugetlb(round_up(size, huge_page_size()), populate)?,
+            #[cfg(not(target_os = "linux"))]
+            _ => { return Err(Error::new(ErrorKind::Other, "huge pages are only supported on linux")); }
+        };
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(node) = node {
+                // Not fatal either: the pages land wherever they're first
+                // touched, as they would without NUMA.
+                #[allow(unused_must_use)]
+                {
+                    mmap.bind_node(node.id());
+                    if config.huge_pages != HugePages::Transparent { mmap.populate(); }
+                }
+            }
+        }
+        let pages = (mmap.size() / config.page_size).min(max_pages);
+        let free = ConcurrentQueue::bounded(pages);
+        for index in 0..pages {
+            #[allow(unused_must_use)]
+            { free.push(index as u32); }
+        }
+        Ok(Arc::new(Arena {
+            base: mmap.as_mut_ptr(),
+            pages,
+            mmap: Mutex::new(Some(mmap)),
+            free,
+            listed: AtomicBool::new(true),
+            pool,
+            slot,
+        }))
+    }
+
+    pub(crate) fn pages(&self) -> usize {
+        self.pages
+    }
+
+    pub(crate) fn is_unused(&self) -> bool {
+        self.free.len() == self.pages
+    }
+
+    pub(crate) fn take(self: &Arc<Arena>) -> Option<Block> {
+        let index = self.free.pop().ok()?;
+        let ptr = unsafe { self.base.add(index as usize * config().page_size) };
+        Some(Block { ptr, arena: self.clone(), index })
+    }
+
+    // Gives the block's page back to its arena, and returns the arena.
+    pub(crate) fn put(block: Block) -> Arc<Arena> {
+        #[allow(unused_must_use)]
+        { block.arena.free.push(block.index); }
+        block.arena
+    }
+
+    // Puts the arena in `available` unless it's already there.
+    pub(crate) fn list(self: &Arc<Arena>, available: &ConcurrentQueue<Arc<Arena>>) {
+        if !self.listed.swap(true, Ordering::SeqCst) {
+            #[allow(unused_must_use)]
+            { available.push(self.clone()); }
+        }
+    }
+
+    // For whoever took the arena off `available`: puts it back if it has
+    // pages left. Otherwise unlists it, then looks again in case a page
+    // came back in between and saw it as still listed.
+    pub(crate) fn relist(self: &Arc<Arena>, available: &ConcurrentQueue<Arc<Arena>>) {
+        if !self.free.is_empty() {
+            #[allow(unused_must_use)]
+            { available.push(self.clone()); }
+            return;
+        }
+        self.listed.store(false, Ordering::SeqCst);
+        if !self.free.is_empty() { self.list(available); }
+    }
+
+    // Unmaps the arena if none of its pages are in use, claiming them all
+    // first so nobody can take one meanwhile. A retired arena has no free
+    // pages, so it drops out of the available queue next time it's seen.
+    pub(crate) fn retire(self: &Arc<Arena>, available: &ConcurrentQueue<Arc<Arena>>) -> bool {
+        let mut claimed = Vec::with_capacity(self.pages);
+        while let Ok(index) = self.free.pop() {
+            claimed.push(index);
+        }
+        if claimed.len() < self.pages {
+            for index in claimed {
+                #[allow(unused_must_use)]
+                { self.free.push(index); }
+            }
+            // Someone may have found it empty meanwhile and unlisted it.
+            self.list(available);
+            return false;
+        }
+        self.mmap.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).take();
+        true
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn round_up(bytes: usize, to: usize) -> usize {
+    bytes.div_ceil(to) * to
+}
+
+// From /proc/meminfo, assuming the usual 2 MiB if we can't tell.
+#[cfg(target_os = "linux")]
+fn huge_page_size() -> usize {
+    static HUGE_PAGE_SIZE: Lazy<usize> = Lazy::new(|| {
+        std::fs::read_to_string("/proc/meminfo").ok()
+            .and_then(|info| {
+                let line = info.lines().find(|line| line.starts_with("Hugepagesize:"))?;
+                let kb: usize = line.split_whitespace().nth(1)?.parse().ok()?;
+                Some(kb * 1024)
+            })
+            .unwrap_or(2 * 1024 * 1024)
+    });
+    *HUGE_PAGE_SIZE
+}
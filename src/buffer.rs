@@ -1,69 +1,278 @@
 use concurrent_queue::ConcurrentQueue;
-use once_cell::sync::Lazy;
+use once_cell::sync::{Lazy, OnceCell};
 use smallvec::SmallVec;
-use crate::mmap::MmapMut;
+#[cfg(feature = "ringbahn")]
+use crate::io::OwnedBuf;
+use crate::arena::Block;
+use crate::numa::NumaNode;
+use crate::pool::{self, Pool, PoolRef, PoolStats};
+use crate::sites::{self, BufferSite, Site};
 
-use std::cmp::min;
+use std::cmp::{max, min};
 use std::cell::UnsafeCell;
-use std::io::Error;
+use std::io::{Error, ErrorKind};
 use std::mem::replace;
+use std::ops::{Deref, DerefMut};
+use std::os::unix::io::RawFd;
+use std::slice;
 
-pub(crate) const PAGE_SIZE: usize = 4096;
+// The kernel won't take more than this many registered buffers.
+#[cfg(feature = "ringbahn")]
+const MAX_REGISTERED: usize = 16384;
 
-struct FreeList {
-    queue: ConcurrentQueue<MmapMut>,
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HugePages {
+    Never,
+    // Arenas are madvise(MADV_HUGEPAGE)d. The kernel may or may not
+    // oblige, and only for the huge-page-aligned stretches of an arena, so
+    // it pays to make them several huge pages long.
+    Transparent,
+    // Arenas are MAP_HUGETLB mappings. Allocation fails once the reserved
+    // huge pages (vm.nr_hugepages) run out.
+    HugeTlb,
 }
 
-static FREE_LIST: Lazy<FreeList> = Lazy::new(|| {
-    FreeList { queue: ConcurrentQueue::unbounded() }
-});
+// How the pool makes pages. Fixed for the life of the process the first
+// time a page is handed out.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PageConfig {
+    // A power of two, and a multiple of the system page size.
+    pub page_size: usize,
+    // How much to map at a time, to carve pages out of. A multiple of the
+    // page size; with huge pages, rounded up to a multiple of those.
+    pub arena_size: usize,
+    pub huge_pages: HugePages,
+}
 
-impl FreeList {
-    fn pop(&self) -> Option<MmapMut> {
-        FREE_LIST.queue.pop().ok()
+impl Default for PageConfig {
+    fn default() -> PageConfig {
+        PageConfig { page_size: 4096, arena_size: 4 * 1024 * 1024, huge_pages: HugePages::Never }
     }
-    #[allow(unused_must_use)]
-    fn push(&self, item: MmapMut) {
-        FREE_LIST.queue.push(item);
+}
+
+static CONFIG: OnceCell<PageConfig> = OnceCell::new();
+
+pub(crate) fn config() -> &'static PageConfig {
+    CONFIG.get_or_init(PageConfig::default)
+}
+
+// Doesn't fix the config: until a page is made, this is what it would be
+// made with if configure() is never called.
+pub(crate) fn page_size() -> usize {
+    CONFIG.get().map_or(PageConfig::default().page_size, |config| config.page_size)
+}
+
+pub(crate) fn os_page_size() -> usize {
+    static OS_PAGE_SIZE: Lazy<usize> = Lazy::new(|| unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize);
+    *OS_PAGE_SIZE
+}
+
+pub(crate) struct Page {
+    block: Block,
+    // Our slot in the ring's registered buffer table, if we have one.
+    pub(crate) index: Option<u16>,
+}
+
+impl Deref for Page {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.block.ptr, page_size()) }
     }
 }
 
-fn page() -> Result<MmapMut, Error> {
-    if let Some(page) = FREE_LIST.pop() {
-        Ok(page)
-    } else {
-        MmapMut::anon(PAGE_SIZE, true)
+impl DerefMut for Page {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.block.ptr, page_size()) }
     }
 }
 
-fn page_out(page: MmapMut) {
-    FREE_LIST.push(page);
+// Pages the kernel has pinned for a ring. They're handed out in preference
+// to the arenas and come back here when the buffer is dropped.
+#[cfg_attr(not(feature = "ringbahn"), allow(dead_code))]
+struct Registered {
+    ring: RawFd,
+    queue: ConcurrentQueue<Page>,
+}
+
+static REGISTERED: OnceCell<Registered> = OnceCell::new();
+
+// Registered pages belong to the default pool, so only it hands them out.
+fn page(pool: &PoolRef) -> Result<Page, Error> {
+    if pool.is_default() {
+        if let Some(page) = REGISTERED.get().and_then(|r| r.queue.pop().ok()) {
+            return Ok(page);
+        }
+    }
+    Ok(Page { block: pool.page()?, index: None })
+}
+
+#[allow(unused_must_use)]
+fn page_out(page: Page) {
+    match (page.index, REGISTERED.get()) {
+        (Some(_), Some(registered)) => { registered.queue.push(page); }
+        _ => pool::release(page.block),
+    }
+}
+
+// Takes `pages` pages out of the arenas for good and hands their iovecs
+// to `register`, which should register them with the ring in order so a
+// page's position is its buffer index. Only one ring gets a registered
+// pool.
+#[cfg(feature = "ringbahn")]
+pub(crate) fn register_pages(
+    ring: RawFd,
+    pages: usize,
+    register: impl FnOnce(&[libc::iovec]) -> Result<(), Error>,
+) -> Result<(), Error> {
+    if pages == 0 || pages > MAX_REGISTERED {
+        return Err(Error::new(ErrorKind::InvalidInput, "registered pool must be 1-16384 pages"));
+    }
+    if REGISTERED.get().is_some() {
+        return Err(Error::new(ErrorKind::AlreadyExists, "a registered pool already exists"));
+    }
+    let mut blocks = Vec::with_capacity(pages);
+    for _ in 0..pages {
+        blocks.push(Pool::default_pool().page()?);
+    }
+    let iovecs: Vec<libc::iovec> = blocks.iter()
+        .map(|b| libc::iovec { iov_base: b.ptr.cast(), iov_len: page_size() })
+        .collect();
+    register(&iovecs)?;
+    let queue = ConcurrentQueue::unbounded();
+    for (index, block) in blocks.into_iter().enumerate() {
+        #[allow(unused_must_use)]
+        { queue.push(Page { block, index: Some(index as u16) }); }
+    }
+    REGISTERED.set(Registered { ring, queue })
+        .map_err(|_| Error::new(ErrorKind::AlreadyExists, "a registered pool already exists"))
+}
+
+// The ring that page indices are valid for, if any.
+#[cfg(feature = "ringbahn")]
+pub(crate) fn registered_ring() -> Option<RawFd> {
+    REGISTERED.get().map(|r| r.ring)
+}
+
+// A single-page Buffer lent out as an OwnedBuf, for operations that
+// want a plain slice. The page sits at a fixed address in an arena that
+// outlives the Buffer, so it doesn't move with the struct.
+#[cfg(feature = "ringbahn")]
+pub(crate) struct PageBuf(pub(crate) Buffer);
+
+#[cfg(feature = "ringbahn")]
+impl AsRef<[u8]> for PageBuf {
+    fn as_ref(&self) -> &[u8] {
+        unsafe { &*self.0.buffer[0].get() }
+    }
+}
+
+#[cfg(feature = "ringbahn")]
+impl AsMut<[u8]> for PageBuf {
+    fn as_mut(&mut self) -> &mut [u8] {
+        unsafe { &mut *self.0.buffer[0].get() }
+    }
 }
 
+#[cfg(feature = "ringbahn")]
+unsafe impl OwnedBuf for PageBuf {}
+
 pub struct Buffer {
-    pub(crate) buffer: SmallVec<[UnsafeCell<MmapMut>; 2]>,
+    pub(crate) buffer: SmallVec<[UnsafeCell<Page>; 2]>,
+    // Where it grows from.
+    pool: PoolRef,
+    // Recorded when we take our first page.
+    site: Site,
 }
 
 impl Buffer {
+    // Sets how pages are made. Has to happen before the first page is
+    // made, after which the page size can't change. Sizes worked out from
+    // page_size() before then assumed the default.
+    pub fn configure(config: PageConfig) -> Result<(), Error> {
+        let size = config.page_size;
+        if !size.is_power_of_two() || !size.is_multiple_of(os_page_size()) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput, "page size must be a power of two and a multiple of the system page size"
+            ));
+        }
+        if config.arena_size == 0 || !config.arena_size.is_multiple_of(size) || config.arena_size / size > u32::MAX as usize {
+            return Err(Error::new(ErrorKind::InvalidInput, "arena size must be a whole number of pages"));
+        }
+        CONFIG.set(config)
+            .map_err(|_| Error::new(ErrorKind::AlreadyExists, "pages are already configured or in use"))
+    }
+
+    pub fn page_size() -> usize {
+        page_size()
+    }
+
+    // The default pool and the NUMA node pools, together.
+    pub fn stats() -> PoolStats {
+        Pool::shared_stats()
+    }
+
+    // Whether to record where each Buffer takes its first page from now
+    // on, for live_sites to report.
+    pub fn record_sites(on: bool) {
+        sites::set_recording(on);
+    }
+
+    // Where the live Buffers made while recording were made.
+    pub fn live_sites() -> Vec<BufferSite> {
+        sites::live()
+    }
+
     pub fn new() -> Buffer {
-        Buffer{ buffer: SmallVec::new() }
+        Buffer::in_pool(PoolRef::Shared(Pool::default_pool()))
+    }
+
+    pub(crate) fn in_pool(pool: PoolRef) -> Buffer {
+        Buffer{ buffer: SmallVec::new(), pool, site: Site::none() }
+    }
+
+    pub(crate) fn push_block(&mut self, block: Block) {
+        self.push_page(Page { block, index: None });
+    }
+
+    fn push_page(&mut self, page: Page) {
+        if self.buffer.is_empty() && self.site.is_none() { self.site = Site::new(); }
+        self.buffer.push(UnsafeCell::new(page));
     }
 
     pub fn capacity(&self) -> usize {
-        self.buffer.len() * PAGE_SIZE
+        self.buffer.len() * page_size()
     }
 
     pub fn with_pages(pages: usize) -> Result<Buffer, Error> {
-        let mut buffer = SmallVec::with_capacity(pages);
+        Buffer::with_pages_in(PoolRef::Shared(Pool::default_pool()), pages)
+    }
+
+    // From `node`'s own pool, which it keeps growing from.
+    pub fn with_pages_on(node: NumaNode, pages: usize) -> Result<Buffer, Error> {
+        Buffer::with_pages_in(PoolRef::Shared(Pool::on_node(node)), pages)
+    }
+
+    // On the node of the CPU we're running on, if there's NUMA.
+    pub fn with_pages_local(pages: usize) -> Result<Buffer, Error> {
+        match NumaNode::current() {
+            Some(node) => Buffer::with_pages_on(node, pages),
+            None => Buffer::with_pages(pages),
+        }
+    }
+
+    // Pages taken before an error go back as the buffer drops.
+    fn with_pages_in(pool: PoolRef, pages: usize) -> Result<Buffer, Error> {
+        let mut buffer = Buffer::in_pool(pool);
+        buffer.buffer.reserve(pages);
         for _ in 0..pages {
-            buffer.push(UnsafeCell::new(page()?));
+            buffer.add_page()?;
         }
-        Ok(Buffer { buffer })
+        Ok(buffer)
     }
 
     pub fn with_capacity(bytes: usize) -> Result<Buffer, Error> {
-        let div = bytes / PAGE_SIZE;
-        let rem = bytes % PAGE_SIZE;
+        let div = bytes / page_size();
+        let rem = bytes % page_size();
         Buffer::with_pages(if rem == 0 { div } else { div + 1 })
     }
 
@@ -77,40 +286,61 @@ impl Buffer {
     }
 
     pub fn reserve_extra(&mut self, bytes: usize) -> Result<(), Error> {
-        let div = bytes / PAGE_SIZE;
-        let blocks = if (bytes % PAGE_SIZE) == 0 { div } else { div + 1 };
+        let div = bytes / page_size();
+        let blocks = if (bytes % page_size()) == 0 { div } else { div + 1 };
         for _ in 0..blocks {
-            self.buffer.push(UnsafeCell::new(page()?));
+            self.push_page(page(&self.pool)?);
         }
         Ok(())
     }
     
     pub fn add_page(&mut self) -> Result<(), Error> {
-        self.buffer.push(UnsafeCell::new(page()?));
+        self.push_page(page(&self.pool)?);
         Ok(())
     }
 
+    #[cfg(feature = "ringbahn")]
+    pub(crate) fn page_index(&self, block: usize) -> Option<u16> {
+        self.buffer.get(block).and_then(|page| unsafe { (*page.get()).index })
+    }
+
+    // Splits off each page as a Buffer of its own, in order.
+    #[cfg(feature = "ringbahn")]
+    pub(crate) fn into_pages(mut self) -> Vec<Buffer> {
+        let pool = self.pool.clone();
+        replace(&mut self.buffer, SmallVec::new())
+            .into_iter()
+            .map(|page| Buffer { buffer: Some(page).into_iter().collect(), pool: pool.clone(), site: self.site.split() })
+            .collect()
+    }
+
+    #[cfg(feature = "ringbahn")]
+    pub(crate) fn append(&mut self, mut other: Buffer) {
+        if self.site.is_none() { self.site = replace(&mut other.site, Site::none()); }
+        self.buffer.extend(replace(&mut other.buffer, SmallVec::new()));
+    }
+
     pub fn read_first(&self, watermark: usize, limit: usize) -> Option<&[u8]> {
-        let block = watermark / PAGE_SIZE;
-        let offset = watermark % PAGE_SIZE;
+        let block = watermark / page_size();
+        let offset = watermark % page_size();
         if block >= self.buffer.len() { return None; }
         unsafe {
-            Some(&(&*self.buffer[block].get())[offset..min(offset + limit, PAGE_SIZE)])
+            Some(&(&*self.buffer[block].get())[offset..min(offset + limit, page_size())])
         }
     }
 
     pub fn read_block(&self, block: usize, limit: usize) -> Option<&[u8]> {
         if block >= self.buffer.len() { return None; }
         unsafe {
-            Some(&(&*self.buffer[block].get())[..min(limit, PAGE_SIZE)])
+            Some(&(&*self.buffer[block].get())[..min(limit, page_size())])
         }
     }
 }
 
 impl Drop for Buffer {
     fn drop(&mut self) {
-        for mmap in replace(&mut self.buffer, SmallVec::new()) {
-            page_out(mmap.into_inner());
+        for page in replace(&mut self.buffer, SmallVec::new()) {
+            page_out(page.into_inner());
         }
     }
 }
@@ -126,7 +356,7 @@ enum RState {
 }
 
 impl<'a> Readable<'a> {
-    pub(crate) fn new(buffer: &'a mut Buffer, from: usize, len: usize) -> Readable<'a> {
+    pub(crate) fn new(buffer: &'a Buffer, from: usize, len: usize) -> Readable<'a> {
         Readable { buffer, state: RState::First(from, len) }
     }
 }
@@ -135,21 +365,21 @@ impl<'a> Iterator for Readable<'a> {
     fn next(&mut self) -> Option<&'a [u8]> {
         match self.state {
             RState::First(watermark, limit) => {
-                let block = watermark / PAGE_SIZE;
-                let offset = watermark % PAGE_SIZE;
+                let block = watermark / page_size();
+                let offset = watermark % page_size();
                 if block >= self.buffer.buffer.len() { return None; }
-                let end = min(offset + limit, PAGE_SIZE);
+                let end = min(offset + limit, page_size());
                 let len = end - offset;
                 self.state = RState::Rest(block + 1, limit - len);
                 unsafe {
-                    Some(&(&*self.buffer.buffer[block].get())[offset..min(offset + limit, PAGE_SIZE)])
+                    Some(&(&*self.buffer.buffer[block].get())[offset..min(offset + limit, page_size())])
                 }
             }
             RState::Rest(block, limit) => {
-                if block >= self.buffer.buffer.len() { return None; }
-                self.state = RState::Rest(block + 1, limit.saturating_sub(PAGE_SIZE));
+                if block >= self.buffer.buffer.len() || limit == 0 { return None; }
+                self.state = RState::Rest(block + 1, limit.saturating_sub(page_size()));
                 unsafe {
-                    Some(&(&*self.buffer.buffer[block].get())[..min(limit, PAGE_SIZE)])
+                    Some(&(&*self.buffer.buffer[block].get())[..min(limit, page_size())])
                 }
             }
         }
@@ -170,11 +400,18 @@ impl<'a> Writeable<'a> {
     pub(crate) fn new(buffer: &'a mut Buffer, from: usize) -> Writeable<'a> {
         Writeable { buffer, state: WState::First(from) }
     }
+    // Writeable never runs out, it just keeps adding pages. This gives
+    // you the rest of what's already allocated (at least one page).
+    pub(crate) fn spare(buffer: &'a mut Buffer, from: usize) -> Result<Vec<&'a mut [u8]>, Error> {
+        let first = from / page_size();
+        let blocks = max(buffer.buffer.len(), first + 1) - first;
+        Writeable::new(buffer, from).take(blocks).collect()
+    }
     pub(crate) fn next_slice(&mut self) -> Result<&'a mut [u8], Error> {
         match self.state {
             WState::First(watermark) => {
-                let block = watermark / PAGE_SIZE;
-                let offset = watermark % PAGE_SIZE;
+                let block = watermark / page_size();
+                let offset = watermark % page_size();
                 while block >= self.buffer.buffer.len() { self.buffer.add_page()?; }
                 self.state = WState::Rest(block + 1);
                 unsafe {
@@ -0,0 +1,141 @@
+// Range copies between files, cheapest way first. A reflink shares the
+// extents outright, copy_file_range(2) keeps the copy in the kernel (and
+// may reflink or offload it by itself), and if neither works we copy
+// through pages on whatever backend the files are on.
+
+#[cfg(target_os = "linux")]
+use blocking::unblock;
+use crate::buffer::Buffer;
+use crate::io::File;
+
+use std::cmp::min;
+use std::io::{Error, ErrorKind};
+#[cfg(target_os = "linux")]
+use std::os::unix::io::{AsRawFd, RawFd};
+
+// How much the paged fallback moves per read and write.
+const CHUNK: usize = 256 * 1024;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CopyStrategy {
+    Reflink,
+    CopyFileRange,
+    Paged,
+}
+
+// _IOW(0x94, 13, struct file_clone_range). The direction bits sit
+// differently on a few architectures.
+#[cfg(target_os = "linux")]
+#[cfg(any(
+    target_arch = "mips",
+    target_arch = "mips64",
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "sparc64",
+))]
+const IOC_WRITE: libc::c_ulong = 4 << 29;
+#[cfg(target_os = "linux")]
+#[cfg(not(any(
+    target_arch = "mips",
+    target_arch = "mips64",
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "sparc64",
+)))]
+const IOC_WRITE: libc::c_ulong = 1 << 30;
+#[cfg(target_os = "linux")]
+const FICLONERANGE: libc::c_ulong = IOC_WRITE | (32 << 16) | (0x94 << 8) | 13;
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct FileCloneRange {
+    src_fd: i64,
+    src_offset: u64,
+    src_length: u64,
+    dest_offset: u64,
+}
+
+// Copies `len` bytes from `src` at `src_off` to `dst` at `dst_off`,
+// stopping early at the end of `src`. Returns how much was copied and how.
+pub(crate) async fn copy_range(
+    src: &File, src_off: u64, dst: &File, dst_off: u64, len: u64
+) -> Result<(u64, CopyStrategy), Error> {
+    #[cfg(target_os = "linux")]
+    {
+        if len > 0 {
+            let (from, to) = (src.as_raw_fd(), dst.as_raw_fd());
+            let copied = unblock(move || -> Result<_, Error> {
+                // Reflinks are all or nothing, and want block-aligned
+                // ranges that don't run past the end of the file, so any
+                // failure just means trying the next thing.
+                if reflink(from, src_off, to, dst_off, len).is_ok() {
+                    return Ok(Some((len, CopyStrategy::Reflink)));
+                }
+                Ok(copy_file_range(from, src_off, to, dst_off, len)?
+                    .map(|copied| (copied, CopyStrategy::CopyFileRange)))
+            }).await?;
+            if let Some(copied) = copied { return Ok(copied); }
+        }
+    }
+    Ok((copy_paged(src, src_off, dst, dst_off, len).await?, CopyStrategy::Paged))
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(from: RawFd, src_off: u64, to: RawFd, dst_off: u64, len: u64) -> Result<(), Error> {
+    let range = FileCloneRange { src_fd: from as i64, src_offset: src_off, src_length: len, dest_offset: dst_off };
+    if unsafe { libc::ioctl(to, FICLONERANGE, &range as *const FileCloneRange) } == -1 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+// None if the kernel can't do this copy at all (too old, or across
+// filesystems it won't copy between) and nothing was copied.
+#[cfg(target_os = "linux")]
+fn copy_file_range(from: RawFd, src_off: u64, to: RawFd, dst_off: u64, len: u64) -> Result<Option<u64>, Error> {
+    let mut off_in = src_off as libc::loff_t;
+    let mut off_out = dst_off as libc::loff_t;
+    let mut copied = 0;
+    while copied < len {
+        let chunk = min(len - copied, isize::MAX as u64) as usize;
+        let ret = unsafe {
+            libc::syscall(libc::SYS_copy_file_range, from, &mut off_in, to, &mut off_out, chunk, 0)
+        };
+        match ret {
+            -1 => {
+                let e = Error::last_os_error();
+                match e.raw_os_error() {
+                    Some(libc::EINTR) => {}
+                    Some(libc::ENOSYS) | Some(libc::EXDEV) | Some(libc::EOPNOTSUPP) | Some(libc::EINVAL)
+                        if copied == 0 => { return Ok(None); }
+                    _ => { return Err(e); }
+                }
+            }
+            0 => break,
+            ret => { copied += ret as u64; }
+        }
+    }
+    Ok(Some(copied))
+}
+
+async fn copy_paged(src: &File, src_off: u64, dst: &File, dst_off: u64, len: u64) -> Result<u64, Error> {
+    let mut buf = Buffer::with_capacity(min(len, CHUNK as u64) as usize)?;
+    let mut copied = 0;
+    while copied < len {
+        let (buf2, read) = src.read_pages(buf, 0, (src_off + copied) as usize).await;
+        buf = buf2;
+        let read = match read {
+            Ok(0) => break,
+            // The last read can run past the end of the range.
+            Ok(read) => min(read as u64, len - copied) as usize,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => { return Err(e); }
+        };
+        let (buf2, wrote) = dst.write_all_pages(buf, 0, read, (dst_off + copied) as usize, false).await;
+        buf = buf2;
+        wrote?;
+        copied += read as u64;
+    }
+    Ok(copied)
+}
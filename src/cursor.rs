@@ -0,0 +1,284 @@
+// Reading and writing a Buffer in memory, for code that wants plain
+// slices or the std (and, with the "bytes" feature, bytes) traits rather
+// than handing it to the I/O methods.
+
+#[cfg(feature = "bytes")]
+use bytes::{buf::UninitSlice, Buf, BufMut};
+use crate::buffer::{Buffer, Readable, Writeable};
+
+use std::cmp::min;
+use std::io::{self, BufRead, Read, Write};
+use std::ops::Range;
+
+// The range of a Buffer page by page.
+pub struct Chunks<'a>(Readable<'a>);
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = &'a [u8];
+    fn next(&mut self) -> Option<&'a [u8]> {
+        self.0.next().filter(|chunk| !chunk.is_empty())
+    }
+}
+
+// Reads a range of a Buffer from the start.
+pub struct BufferReader<'a> {
+    buffer: &'a Buffer,
+    pos: usize,
+    end: usize,
+}
+
+// Writes into a Buffer from some position on, adding pages as it goes.
+pub struct BufferWriter<'a> {
+    buffer: &'a mut Buffer,
+    pos: usize,
+}
+
+impl Buffer {
+    // Ranges are cut short at the end of the capacity.
+    pub fn chunks(&self, range: Range<usize>) -> Chunks<'_> {
+        let (start, end) = self.clamp(range);
+        Chunks(Readable::new(self, start, end - start))
+    }
+
+    pub fn reader(&self, range: Range<usize>) -> BufferReader<'_> {
+        let (pos, end) = self.clamp(range);
+        BufferReader { buffer: self, pos, end }
+    }
+
+    pub fn writer(&mut self, from: usize) -> BufferWriter<'_> {
+        BufferWriter { buffer: self, pos: from }
+    }
+
+    fn clamp(&self, range: Range<usize>) -> (usize, usize) {
+        let end = min(range.end, self.capacity());
+        (min(range.start, end), end)
+    }
+}
+
+impl<'a> BufferReader<'a> {
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.end - self.pos
+    }
+
+    // The rest of the current page's worth, up to the end.
+    pub fn chunk(&self) -> &'a [u8] {
+        self.buffer.read_first(self.pos, self.remaining()).unwrap_or(&[])
+    }
+
+    pub fn advance(&mut self, bytes: usize) {
+        assert!(bytes <= self.remaining(), "advanced {} bytes past the end", bytes - self.remaining());
+        self.pos += bytes;
+    }
+}
+
+impl Read for BufferReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut read = 0;
+        while read < buf.len() && self.remaining() > 0 {
+            let chunk = self.chunk();
+            let len = min(chunk.len(), buf.len() - read);
+            buf[read..read + len].copy_from_slice(&chunk[..len]);
+            self.pos += len;
+            read += len;
+        }
+        Ok(read)
+    }
+}
+
+impl BufRead for BufferReader<'_> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        Ok(BufferReader::chunk(self))
+    }
+
+    fn consume(&mut self, bytes: usize) {
+        self.advance(min(bytes, self.remaining()));
+    }
+}
+
+#[cfg(feature = "bytes")]
+impl Buf for BufferReader<'_> {
+    fn remaining(&self) -> usize {
+        BufferReader::remaining(self)
+    }
+
+    fn chunk(&self) -> &[u8] {
+        BufferReader::chunk(self)
+    }
+
+    fn advance(&mut self, bytes: usize) {
+        BufferReader::advance(self, bytes)
+    }
+}
+
+impl BufferWriter<'_> {
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    // The rest of the page we're in, adding it if need be.
+    pub(crate) fn spare(&mut self) -> io::Result<&mut [u8]> {
+        Writeable::new(self.buffer, self.pos).next_slice()
+    }
+}
+
+impl Write for BufferWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut wrote = 0;
+        while wrote < buf.len() {
+            let spare = match self.spare() {
+                Ok(spare) => spare,
+                // Whatever made it in still counts.
+                Err(_) if wrote > 0 => break,
+                Err(e) => { return Err(e); }
+            };
+            let len = min(spare.len(), buf.len() - wrote);
+            spare[..len].copy_from_slice(&buf[wrote..wrote + len]);
+            self.pos += len;
+            wrote += len;
+        }
+        Ok(wrote)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// As for WriteBuffer, running out of pages panics.
+#[cfg(feature = "bytes")]
+unsafe impl BufMut for BufferWriter<'_> {
+    fn remaining_mut(&self) -> usize {
+        usize::MAX - self.pos
+    }
+
+    fn chunk_mut(&mut self) -> &mut UninitSlice {
+        uninit(self.spare().expect("couldn't add a page to the buffer"))
+    }
+
+    unsafe fn advance_mut(&mut self, bytes: usize) {
+        self.pos += bytes;
+    }
+}
+
+// Pages are mapped zeroed, so they're never actually uninitialised.
+#[cfg(feature = "bytes")]
+pub(crate) fn uninit(slice: &mut [u8]) -> &mut UninitSlice {
+    unsafe { UninitSlice::from_raw_parts_mut(slice.as_mut_ptr(), slice.len()) }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::buffer::Buffer;
+    use crate::pool::{BufferPool, PoolConfig};
+
+    use std::io::{BufRead, ErrorKind, Read, Write};
+
+    // Different on every page, so a slip by a page shows.
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn writes_across_pages() {
+        let page = Buffer::page_size();
+        let data = data(2 * page + 100);
+        let mut buffer = Buffer::new();
+        let mut writer = buffer.writer(10);
+        writer.write_all(&data).unwrap();
+        assert_eq!(writer.position(), 10 + data.len());
+        assert_eq!(buffer.capacity(), 3 * page);
+        let chunks: Vec<&[u8]> = buffer.chunks(10..10 + data.len()).collect();
+        assert_eq!(chunks.iter().map(|chunk| chunk.len()).collect::<Vec<_>>(), [page - 10, page, 110]);
+        assert_eq!(chunks.concat(), data);
+    }
+
+    #[test]
+    fn write_short_at_pool_limit() {
+        let page = Buffer::page_size();
+        let pool = BufferPool::new(PoolConfig { max_pages: 1, ..PoolConfig::default() }).unwrap();
+        let mut buffer = pool.try_acquire(1).unwrap();
+        let mut writer = buffer.writer(page - 4);
+        assert_eq!(writer.write(&[1; 8]).unwrap(), 4);
+        assert_eq!(writer.position(), page);
+        assert_eq!(writer.write(&[1; 8]).unwrap_err().kind(), ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn ranges_past_capacity() {
+        let page = Buffer::page_size();
+        let buffer = Buffer::with_pages(2).unwrap();
+        assert_eq!(buffer.chunks(0..usize::MAX).map(|chunk| chunk.len()).sum::<usize>(), 2 * page);
+        assert_eq!(buffer.chunks(3 * page..4 * page).count(), 0);
+        assert_eq!(buffer.chunks(5..5).count(), 0);
+        let reader = buffer.reader(page + 1..usize::MAX);
+        assert_eq!((reader.position(), reader.remaining()), (page + 1, page - 1));
+        let reader = buffer.reader(3 * page..4 * page);
+        assert_eq!(reader.remaining(), 0);
+        assert!(reader.chunk().is_empty());
+        assert_eq!(Buffer::new().reader(0..10).remaining(), 0);
+    }
+
+    #[test]
+    fn reads_across_pages() {
+        let page = Buffer::page_size();
+        let data = data(2 * page);
+        let mut buffer = Buffer::new();
+        buffer.writer(0).write_all(&data).unwrap();
+        let mut reader = buffer.reader(page - 3..page + 3);
+        let mut out = [0; 4];
+        assert_eq!(reader.read(&mut out).unwrap(), 4);
+        assert_eq!(out, data[page - 3..page + 1]);
+        assert_eq!(reader.read(&mut out).unwrap(), 2);
+        assert_eq!(out[..2], data[page + 1..page + 3]);
+        assert_eq!(reader.read(&mut out).unwrap(), 0);
+    }
+
+    #[test]
+    fn buf_read() {
+        let page = Buffer::page_size();
+        let data = data(2 * page);
+        let mut buffer = Buffer::new();
+        buffer.writer(0).write_all(&data).unwrap();
+        let mut reader = buffer.reader(page - 3..page + 3);
+        assert_eq!(reader.fill_buf().unwrap(), &data[page - 3..page]);
+        reader.consume(3);
+        assert_eq!(reader.fill_buf().unwrap(), &data[page..page + 3]);
+        // Consuming past the end stops at it.
+        reader.consume(10);
+        assert_eq!(reader.position(), page + 3);
+        assert_eq!(reader.fill_buf().unwrap(), b"");
+    }
+
+    #[test]
+    #[should_panic(expected = "advanced 1 bytes past the end")]
+    fn advance_past_end() {
+        let buffer = Buffer::with_pages(1).unwrap();
+        buffer.reader(0..4).advance(5);
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn bytes() {
+        use bytes::{Buf, BufMut};
+
+        let page = Buffer::page_size();
+        let mut buffer = Buffer::new();
+        let mut writer = buffer.writer(page - 2);
+        writer.put_u32(0x01020304);
+        writer.put_slice(b"xyz");
+        assert_eq!(writer.position(), page + 5);
+        assert_eq!(buffer.capacity(), 2 * page);
+        let mut reader = buffer.reader(page - 2..usize::MAX);
+        assert_eq!(Buf::remaining(&reader), 2 * page - (page - 2));
+        assert_eq!(Buf::chunk(&reader).len(), 2);
+        assert_eq!(reader.get_u32(), 0x01020304);
+        let mut rest = [0; 3];
+        reader.copy_to_slice(&mut rest);
+        assert_eq!(&rest, b"xyz");
+        assert_eq!(reader.position(), page + 5);
+    }
+}
@@ -0,0 +1,107 @@
+// O_DIRECT skips the page cache, and in exchange the kernel wants the
+// memory, file offset and length of every transfer aligned, or it fails
+// the whole thing with EINVAL. Buffer pages are page-aligned mappings
+// already, so what's left to check is where in them we start and how
+// much we move.
+
+#[cfg(target_os = "linux")]
+use crate::buffer::os_page_size;
+#[cfg(target_os = "linux")]
+use crate::fs::{statx_fd, STATX_DIOALIGN};
+
+use std::io::{Error, ErrorKind};
+#[cfg(target_os = "linux")]
+use std::fs::OpenOptions;
+#[cfg(target_os = "linux")]
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::RawFd;
+
+// What a file opened for direct I/O needs transfers aligned to.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DirectAlign {
+    // Of the buffer in memory.
+    pub memory: u32,
+    // Of the offset in the file and the length.
+    pub offset: u32,
+}
+
+impl DirectAlign {
+    // `memory` is the position in the Buffer, whose pages are aligned to
+    // more than any device asks for.
+    pub(crate) fn check(&self, memory: usize, offset: usize, len: usize) -> Result<(), Error> {
+        if !memory.is_multiple_of(self.memory as usize) {
+            Err(misaligned("buffer position", memory, self.memory))
+        } else if !offset.is_multiple_of(self.offset as usize) {
+            Err(misaligned("file offset", offset, self.offset))
+        } else if !len.is_multiple_of(self.offset as usize) {
+            Err(misaligned("length", len, self.offset))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+fn misaligned(what: &str, value: usize, align: u32) -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        format!("{} {} is not a multiple of {} as direct I/O requires", what, value, align),
+    )
+}
+
+#[cfg(target_os = "linux")]
+pub trait DirectIo {
+    // Opens with O_DIRECT. This sets custom_flags, so it replaces any
+    // other custom flags rather than adding to them.
+    fn direct(&mut self, direct: bool) -> &mut Self;
+}
+
+#[cfg(target_os = "linux")]
+impl DirectIo for OpenOptions {
+    fn direct(&mut self, direct: bool) -> &mut Self {
+        self.custom_flags(if direct { libc::O_DIRECT } else { 0 })
+    }
+}
+
+// Some if `fd` was opened with O_DIRECT. Kernels before 6.1 can't tell us
+// the alignment, so we assume the filesystem block size, which is never
+// smaller than the device's logical block size.
+#[cfg(target_os = "linux")]
+pub(crate) fn alignment(fd: RawFd) -> Option<DirectAlign> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags == -1 || flags & libc::O_DIRECT == 0 { return None; }
+    Some(match statx_fd(fd, STATX_DIOALIGN) {
+        Ok(statx) if statx.stx_mask & STATX_DIOALIGN != 0 && statx.stx_dio_offset_align != 0 => {
+            DirectAlign { memory: statx.stx_dio_mem_align, offset: statx.stx_dio_offset_align }
+        }
+        Ok(statx) if statx.stx_blksize.is_power_of_two() => {
+            DirectAlign { memory: statx.stx_blksize, offset: statx.stx_blksize }
+        }
+        _ => DirectAlign { memory: os_page_size() as u32, offset: os_page_size() as u32 },
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn alignment(_fd: RawFd) -> Option<DirectAlign> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DirectAlign;
+
+    use std::io::ErrorKind;
+
+    #[test]
+    fn check() {
+        let align = DirectAlign { memory: 512, offset: 4096 };
+        assert!(align.check(0, 0, 0).is_ok());
+        assert!(align.check(512, 8192, 4096).is_ok());
+        let cases = [(100, 0, 4096, "buffer position"), (0, 512, 4096, "file offset"), (0, 4096, 512, "length")];
+        for (memory, offset, len, what) in cases.iter().copied() {
+            let e = align.check(memory, offset, len).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::InvalidInput);
+            assert!(e.to_string().starts_with(what));
+        }
+    }
+}
+
@@ -0,0 +1,244 @@
+// Filesystem operations on the threadpool. The ring does statx, rename,
+// unlink, mkdir and the links natively when the kernel has them, and
+// falls back to these otherwise.
+
+use blocking::unblock;
+
+use std::ffi::CString;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::ops::BitOr;
+use std::os::unix::ffi::OsStrExt;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+
+pub(crate) fn cstring(path: &Path) -> Result<CString, Error> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, "path contains a nul byte"))
+}
+
+#[cfg(target_os = "linux")]
+pub const STATX_BASIC_STATS: u32 = 0x7ff;
+#[cfg(target_os = "linux")]
+pub const STATX_ALL: u32 = 0xfff;
+// stx_dio_mem_align and stx_dio_offset_align, from Linux 6.1. Not part of
+// STATX_ALL.
+#[cfg(target_os = "linux")]
+pub const STATX_DIOALIGN: u32 = 0x2000;
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StatxTimestamp {
+    pub tv_sec: i64,
+    pub tv_nsec: u32,
+    reserved: i32,
+}
+
+// struct statx, as the kernel lays it out. Our own copy because libc's
+// doesn't exist on every target and lags behind the kernel's fields.
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Statx {
+    pub stx_mask: u32,
+    pub stx_blksize: u32,
+    pub stx_attributes: u64,
+    pub stx_nlink: u32,
+    pub stx_uid: u32,
+    pub stx_gid: u32,
+    pub stx_mode: u16,
+    spare0: u16,
+    pub stx_ino: u64,
+    pub stx_size: u64,
+    pub stx_blocks: u64,
+    pub stx_attributes_mask: u64,
+    pub stx_atime: StatxTimestamp,
+    pub stx_btime: StatxTimestamp,
+    pub stx_ctime: StatxTimestamp,
+    pub stx_mtime: StatxTimestamp,
+    pub stx_rdev_major: u32,
+    pub stx_rdev_minor: u32,
+    pub stx_dev_major: u32,
+    pub stx_dev_minor: u32,
+    pub stx_mnt_id: u64,
+    pub stx_dio_mem_align: u32,
+    pub stx_dio_offset_align: u32,
+    spare3: [u64; 12],
+}
+
+#[cfg(target_os = "linux")]
+impl Statx {
+    pub fn len(&self) -> u64 {
+        self.stx_size
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.stx_mode as libc::mode_t & libc::S_IFMT == libc::S_IFDIR
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.stx_mode as libc::mode_t & libc::S_IFMT == libc::S_IFREG
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.stx_mode as libc::mode_t & libc::S_IFMT == libc::S_IFLNK
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) async fn statx(path: PathBuf, mask: u32) -> Result<Statx, Error> {
+    unblock(move || {
+        let path = cstring(&path)?;
+        let mut statx = Statx::default();
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_statx, libc::AT_FDCWD, path.as_ptr(), 0, mask, &mut statx as *mut Statx
+            )
+        };
+        if ret == -1 {
+            Err(Error::last_os_error())
+        } else {
+            Ok(statx)
+        }
+    }).await
+}
+
+// statx of an open descriptor. Blocking.
+#[cfg(target_os = "linux")]
+pub(crate) fn statx_fd(fd: RawFd, mask: u32) -> Result<Statx, Error> {
+    let mut statx = Statx::default();
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_statx, fd, b"\0".as_ptr(), libc::AT_EMPTY_PATH, mask, &mut statx as *mut Statx
+        )
+    };
+    if ret == -1 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(statx)
+    }
+}
+
+// Flags for renameat2(2).
+#[cfg(target_os = "linux")]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct RenameFlags(u32);
+
+#[cfg(target_os = "linux")]
+impl RenameFlags {
+    // Fail with AlreadyExists rather than replace the target.
+    pub const NOREPLACE: RenameFlags = RenameFlags(1);
+    // Atomically swap the two paths, both of which must exist.
+    pub const EXCHANGE: RenameFlags = RenameFlags(2);
+
+    pub fn empty() -> RenameFlags {
+        RenameFlags(0)
+    }
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl BitOr for RenameFlags {
+    type Output = RenameFlags;
+    fn bitor(self, other: RenameFlags) -> RenameFlags {
+        RenameFlags(self.0 | other.0)
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) async fn rename_with(from: PathBuf, to: PathBuf, flags: RenameFlags) -> Result<(), Error> {
+    unblock(move || {
+        let (from, to) = (cstring(&from)?, cstring(&to)?);
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_renameat2, libc::AT_FDCWD, from.as_ptr(), libc::AT_FDCWD, to.as_ptr(), flags.0
+            )
+        };
+        if ret == -1 {
+            Err(Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }).await
+}
+
+// Flags for sync_file_range(2). None of them make the data durable: there
+// is no metadata flush and no device cache flush, so it's for pacing
+// writeback ahead of a real fsync rather than replacing one.
+#[cfg(target_os = "linux")]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SyncRangeFlags(u32);
+
+#[cfg(target_os = "linux")]
+impl SyncRangeFlags {
+    // Wait for writeback already in flight on the range to finish.
+    pub const WAIT_BEFORE: SyncRangeFlags = SyncRangeFlags(1);
+    // Start writeback of dirty pages in the range.
+    pub const WRITE: SyncRangeFlags = SyncRangeFlags(2);
+    // Wait for the writeback to finish.
+    pub const WAIT_AFTER: SyncRangeFlags = SyncRangeFlags(4);
+
+    pub fn empty() -> SyncRangeFlags {
+        SyncRangeFlags(0)
+    }
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl BitOr for SyncRangeFlags {
+    type Output = SyncRangeFlags;
+    fn bitor(self, other: SyncRangeFlags) -> SyncRangeFlags {
+        SyncRangeFlags(self.0 | other.0)
+    }
+}
+
+// Blocking; a `len` of 0 means through the end of the file.
+#[cfg(target_os = "linux")]
+pub(crate) fn sync_range(fd: RawFd, offset: u64, len: u64, flags: SyncRangeFlags) -> Result<(), Error> {
+    let ret = unsafe { libc::sync_file_range(fd, offset as libc::off64_t, len as libc::off64_t, flags.0) };
+    if ret == -1 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+pub(crate) async fn metadata(path: PathBuf) -> Result<fs::Metadata, Error> {
+    unblock(move || fs::metadata(path)).await
+}
+
+pub(crate) async fn rename(from: PathBuf, to: PathBuf) -> Result<(), Error> {
+    unblock(move || fs::rename(from, to)).await
+}
+
+pub(crate) async fn unlink(path: PathBuf) -> Result<(), Error> {
+    unblock(move || fs::remove_file(path)).await
+}
+
+pub(crate) async fn mkdir(path: PathBuf) -> Result<(), Error> {
+    unblock(move || fs::create_dir(path)).await
+}
+
+pub(crate) async fn remove_dir(path: PathBuf) -> Result<(), Error> {
+    unblock(move || fs::remove_dir(path)).await
+}
+
+pub(crate) async fn hard_link(src: PathBuf, dst: PathBuf) -> Result<(), Error> {
+    unblock(move || fs::hard_link(src, dst)).await
+}
+
+pub(crate) async fn symlink(src: PathBuf, dst: PathBuf) -> Result<(), Error> {
+    unblock(move || std::os::unix::fs::symlink(src, dst)).await
+}
+
+pub(crate) async fn read_link(path: PathBuf) -> Result<PathBuf, Error> {
+    unblock(move || fs::read_link(path)).await
+}
@@ -0,0 +1,469 @@
+use crate::buffer::Buffer;
+use crate::copy::{self, CopyStrategy};
+use crate::direct::DirectAlign;
+#[cfg(target_os = "linux")]
+use crate::fs::{RenameFlags, Statx, SyncRangeFlags};
+use crate::legacy;
+use crate::net::{TcpListener, TcpStream, UdpSocket};
+#[cfg(feature = "ringbahn")]
+use crate::ringbahn;
+use crate::unix::{UnixDatagram, UnixListener, UnixStream};
+
+use std::fs::{self, OpenOptions};
+use std::io::Error;
+use std::net::SocketAddr;
+#[cfg(target_os = "linux")]
+use std::ops::Range;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+
+// The backend-agnostic entry point. Each variant wraps the handle of the
+// backend that does the actual work, so callers only need to cfg-gate if
+// they want to reach for something backend-specific.
+#[derive(Clone)]
+pub enum IO {
+    Legacy(legacy::IO),
+    #[cfg(feature = "ringbahn")]
+    Ringbahn(ringbahn::IO),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Backend {
+    Legacy,
+    Ringbahn,
+}
+
+pub enum File {
+    Legacy(legacy::File),
+    #[cfg(feature = "ringbahn")]
+    Ringbahn(ringbahn::File),
+}
+
+/// An owned buffer we can lend to the kernel.
+///
+/// # Safety
+///
+/// The bytes must stay where they are when the buffer itself is moved,
+/// because with io_uring the kernel may still be using them after the
+/// future is dropped.
+pub unsafe trait OwnedBuf: AsRef<[u8]> + AsMut<[u8]> + Send + Unpin + 'static {}
+
+unsafe impl OwnedBuf for Vec<u8> {}
+unsafe impl OwnedBuf for Box<[u8]> {}
+
+impl IO {
+    // Uses io_uring if it was compiled in and the kernel will let us have
+    // it, otherwise falls back to the threadpool.
+    pub fn new() -> IO {
+        #[cfg(feature = "ringbahn")]
+        {
+            if let Some(io) = ringbahn::IO::probe() {
+                return IO::Ringbahn(io);
+            }
+        }
+        IO::legacy()
+    }
+
+    pub fn legacy() -> IO {
+        IO::Legacy(legacy::IO::default())
+    }
+
+    pub fn backend(&self) -> Backend {
+        match self {
+            IO::Legacy(_) => Backend::Legacy,
+            #[cfg(feature = "ringbahn")]
+            IO::Ringbahn(_) => Backend::Ringbahn,
+        }
+    }
+
+    // Pins a pool of pages for io_uring's fixed buffer operations. There's
+    // nothing to pin on the threadpool, so this is a no-op there.
+    #[cfg_attr(not(feature = "ringbahn"), allow(unused_variables))]
+    pub fn register_buffers(&self, pages: usize) -> Result<(), Error> {
+        match self {
+            IO::Legacy(_) => Ok(()),
+            #[cfg(feature = "ringbahn")]
+            IO::Ringbahn(io) => io.register_buffers(pages),
+        }
+    }
+
+    // Sets up io_uring's registered file table. A no-op on the threadpool.
+    #[cfg_attr(not(feature = "ringbahn"), allow(unused_variables))]
+    pub fn register_files(&self, slots: u32) -> Result<(), Error> {
+        match self {
+            IO::Legacy(_) => Ok(()),
+            #[cfg(feature = "ringbahn")]
+            IO::Ringbahn(io) => io.register_files(slots),
+        }
+    }
+
+    pub async fn create_file(&self, path: impl AsRef<Path>) -> Result<File, Error> {
+        match self {
+            IO::Legacy(io) => Ok(File::Legacy(io.create_file(path).await?)),
+            #[cfg(feature = "ringbahn")]
+            IO::Ringbahn(io) => Ok(File::Ringbahn(io.create_file(path).await?)),
+        }
+    }
+
+    pub async fn open_file(&self, path: impl AsRef<Path>, opts: &OpenOptions) -> Result<File, Error> {
+        match self {
+            IO::Legacy(io) => Ok(File::Legacy(io.open_file(path, opts).await?)),
+            #[cfg(feature = "ringbahn")]
+            IO::Ringbahn(io) => Ok(File::Ringbahn(io.open_file(path, opts).await?)),
+        }
+    }
+
+    pub fn from_file(&self, file: fs::File) -> File {
+        match self {
+            IO::Legacy(io) => File::Legacy(io.from_file(file)),
+            #[cfg(feature = "ringbahn")]
+            IO::Ringbahn(io) => File::Ringbahn(io.from_file(file)),
+        }
+    }
+
+    // Sockets use io_uring's ACCEPT/CONNECT/SEND/RECV where the kernel has
+    // them (5.6), otherwise they go to the threadpool even on a ring.
+    pub async fn bind_tcp(&self, addr: SocketAddr) -> Result<TcpListener, Error> {
+        match self {
+            #[cfg(feature = "ringbahn")]
+            IO::Ringbahn(io) if io.supports_net() => Ok(TcpListener::Ringbahn(io.bind_tcp(addr).await?)),
+            _ => Ok(TcpListener::Legacy(legacy::IO::default().bind_tcp(addr).await?)),
+        }
+    }
+
+    pub async fn connect_tcp(&self, addr: SocketAddr) -> Result<TcpStream, Error> {
+        match self {
+            #[cfg(feature = "ringbahn")]
+            IO::Ringbahn(io) if io.supports_net() => Ok(TcpStream::Ringbahn(io.connect_tcp(addr).await?)),
+            _ => Ok(TcpStream::Legacy(legacy::IO::default().connect_tcp(addr).await?)),
+        }
+    }
+
+    pub async fn bind_udp(&self, addr: SocketAddr) -> Result<UdpSocket, Error> {
+        match self {
+            #[cfg(feature = "ringbahn")]
+            IO::Ringbahn(io) if io.supports_net() => Ok(UdpSocket::Ringbahn(io.bind_udp(addr).await?)),
+            _ => Ok(UdpSocket::Legacy(legacy::IO::default().bind_udp(addr).await?)),
+        }
+    }
+
+    pub async fn bind_unix(&self, path: impl AsRef<Path>) -> Result<UnixListener, Error> {
+        match self {
+            #[cfg(feature = "ringbahn")]
+            IO::Ringbahn(io) if io.supports_net() => Ok(UnixListener::Ringbahn(io.bind_unix(path).await?)),
+            _ => Ok(UnixListener::Legacy(legacy::IO::default().bind_unix(path).await?)),
+        }
+    }
+
+    pub async fn connect_unix(&self, path: impl AsRef<Path>) -> Result<UnixStream, Error> {
+        match self {
+            #[cfg(feature = "ringbahn")]
+            IO::Ringbahn(io) if io.supports_net() => Ok(UnixStream::Ringbahn(io.connect_unix(path).await?)),
+            _ => Ok(UnixStream::Legacy(legacy::IO::default().connect_unix(path).await?)),
+        }
+    }
+
+    // A connected pair, e.g. to hand one end to a child process.
+    pub fn unix_pair(&self) -> Result<(UnixStream, UnixStream), Error> {
+        match self {
+            #[cfg(feature = "ringbahn")]
+            IO::Ringbahn(io) if io.supports_net() => {
+                let (a, b) = io.unix_pair()?;
+                Ok((UnixStream::Ringbahn(a), UnixStream::Ringbahn(b)))
+            }
+            _ => {
+                let (a, b) = legacy::IO::default().unix_pair()?;
+                Ok((UnixStream::Legacy(a), UnixStream::Legacy(b)))
+            }
+        }
+    }
+
+    pub async fn bind_unix_datagram(&self, path: impl AsRef<Path>) -> Result<UnixDatagram, Error> {
+        match self {
+            #[cfg(feature = "ringbahn")]
+            IO::Ringbahn(io) if io.supports_net() => Ok(UnixDatagram::Ringbahn(io.bind_unix_datagram(path).await?)),
+            _ => Ok(UnixDatagram::Legacy(legacy::IO::default().bind_unix_datagram(path).await?)),
+        }
+    }
+
+    pub fn unix_datagram_pair(&self) -> Result<(UnixDatagram, UnixDatagram), Error> {
+        match self {
+            #[cfg(feature = "ringbahn")]
+            IO::Ringbahn(io) if io.supports_net() => {
+                let (a, b) = io.unix_datagram_pair()?;
+                Ok((UnixDatagram::Ringbahn(a), UnixDatagram::Ringbahn(b)))
+            }
+            _ => {
+                let (a, b) = legacy::IO::default().unix_datagram_pair()?;
+                Ok((UnixDatagram::Legacy(a), UnixDatagram::Legacy(b)))
+            }
+        }
+    }
+
+    pub async fn metadata(&self, path: impl AsRef<Path>) -> Result<fs::Metadata, Error> {
+        crate::fs::metadata(path.as_ref().to_owned()).await
+    }
+
+    // Only the fields in `mask` are guaranteed to be filled in; check
+    // stx_mask for what the filesystem actually provided.
+    #[cfg(target_os = "linux")]
+    pub async fn statx(&self, path: impl AsRef<Path>, mask: u32) -> Result<Statx, Error> {
+        match self {
+            IO::Legacy(io) => io.statx(path, mask).await,
+            #[cfg(feature = "ringbahn")]
+            IO::Ringbahn(io) => io.statx(path, mask).await,
+        }
+    }
+
+    // Moves up to `len` bytes from `from` to `to` inside the kernel. One
+    // side must be a pipe, and its offset must be None; a None offset on
+    // a file uses and advances its position.
+    #[cfg(target_os = "linux")]
+    pub async fn splice(
+        &self, from: &impl AsRawFd, from_off: Option<u64>, to: &impl AsRawFd, to_off: Option<u64>, len: usize
+    ) -> Result<usize, Error> {
+        let (from, to) = (from.as_raw_fd(), to.as_raw_fd());
+        match self {
+            IO::Legacy(io) => io.splice(from, from_off, to, to_off, len).await,
+            #[cfg(feature = "ringbahn")]
+            IO::Ringbahn(io) => io.splice(from, from_off, to, to_off, len).await,
+        }
+    }
+
+    // Duplicates up to `len` bytes from one pipe into another, leaving
+    // them to be read from `from` as well.
+    #[cfg(target_os = "linux")]
+    pub async fn tee(&self, from: &impl AsRawFd, to: &impl AsRawFd, len: usize) -> Result<usize, Error> {
+        let (from, to) = (from.as_raw_fd(), to.as_raw_fd());
+        match self {
+            IO::Legacy(io) => io.tee(from, to, len).await,
+            #[cfg(feature = "ringbahn")]
+            IO::Ringbahn(io) => io.tee(from, to, len).await,
+        }
+    }
+
+    // Copies `len` bytes of `src` at `src_off` into `dst` at `dst_off`,
+    // sharing extents with a reflink if the filesystem can, otherwise with
+    // copy_file_range(2), otherwise through pages. Stops early at the end
+    // of `src`; returns how much was copied and which of those it took.
+    pub async fn copy_range(
+        &self, src: &File, src_off: u64, dst: &File, dst_off: u64, len: u64
+    ) -> Result<(u64, CopyStrategy), Error> {
+        copy::copy_range(src, src_off, dst, dst_off, len).await
+    }
+
+    pub async fn rename(&self, from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<(), Error> {
+        match self {
+            IO::Legacy(_) => crate::fs::rename(from.as_ref().to_owned(), to.as_ref().to_owned()).await,
+            #[cfg(feature = "ringbahn")]
+            IO::Ringbahn(io) => io.rename(from, to).await,
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    pub async fn rename_with(
+        &self, from: impl AsRef<Path>, to: impl AsRef<Path>, flags: RenameFlags
+    ) -> Result<(), Error> {
+        match self {
+            IO::Legacy(_) => crate::fs::rename_with(from.as_ref().to_owned(), to.as_ref().to_owned(), flags).await,
+            #[cfg(feature = "ringbahn")]
+            IO::Ringbahn(io) => io.rename_with(from, to, flags).await,
+        }
+    }
+
+    pub async fn unlink(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        match self {
+            IO::Legacy(_) => crate::fs::unlink(path.as_ref().to_owned()).await,
+            #[cfg(feature = "ringbahn")]
+            IO::Ringbahn(io) => io.unlink(path).await,
+        }
+    }
+
+    pub async fn mkdir(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        match self {
+            IO::Legacy(_) => crate::fs::mkdir(path.as_ref().to_owned()).await,
+            #[cfg(feature = "ringbahn")]
+            IO::Ringbahn(io) => io.mkdir(path).await,
+        }
+    }
+
+    pub async fn remove_dir(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        match self {
+            IO::Legacy(_) => crate::fs::remove_dir(path.as_ref().to_owned()).await,
+            #[cfg(feature = "ringbahn")]
+            IO::Ringbahn(io) => io.remove_dir(path).await,
+        }
+    }
+
+    pub async fn hard_link(&self, src: impl AsRef<Path>, dst: impl AsRef<Path>) -> Result<(), Error> {
+        match self {
+            IO::Legacy(_) => crate::fs::hard_link(src.as_ref().to_owned(), dst.as_ref().to_owned()).await,
+            #[cfg(feature = "ringbahn")]
+            IO::Ringbahn(io) => io.hard_link(src, dst).await,
+        }
+    }
+
+    // Creates `dst` pointing at `src`.
+    pub async fn symlink(&self, src: impl AsRef<Path>, dst: impl AsRef<Path>) -> Result<(), Error> {
+        match self {
+            IO::Legacy(_) => crate::fs::symlink(src.as_ref().to_owned(), dst.as_ref().to_owned()).await,
+            #[cfg(feature = "ringbahn")]
+            IO::Ringbahn(io) => io.symlink(src, dst).await,
+        }
+    }
+
+    pub async fn read_link(&self, path: impl AsRef<Path>) -> Result<PathBuf, Error> {
+        crate::fs::read_link(path.as_ref().to_owned()).await
+    }
+}
+
+impl Default for IO {
+    fn default() -> IO {
+        IO::new()
+    }
+}
+
+impl From<legacy::File> for File {
+    fn from(file: legacy::File) -> File {
+        File::Legacy(file)
+    }
+}
+
+#[cfg(feature = "ringbahn")]
+impl From<ringbahn::File> for File {
+    fn from(file: ringbahn::File) -> File {
+        File::Ringbahn(file)
+    }
+}
+
+impl AsRawFd for File {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            File::Legacy(file) => file.as_raw_fd(),
+            #[cfg(feature = "ringbahn")]
+            File::Ringbahn(file) => file.as_raw_fd(),
+        }
+    }
+}
+
+impl File {
+    // Opts this file into the registered file table. Returns whether it is
+    // registered, which it never is on the threadpool.
+    pub fn register(&mut self) -> Result<bool, Error> {
+        match self {
+            File::Legacy(_) => Ok(false),
+            #[cfg(feature = "ringbahn")]
+            File::Ringbahn(file) => file.register(),
+        }
+    }
+
+    pub fn unregister(&mut self) -> Result<(), Error> {
+        match self {
+            File::Legacy(_) => Ok(()),
+            #[cfg(feature = "ringbahn")]
+            File::Ringbahn(file) => file.unregister(),
+        }
+    }
+
+    // Some if the file was opened for direct I/O, with what its transfers
+    // need aligning to. ReadBuffer and WriteBuffer check against this
+    // before going to the kernel.
+    pub fn direct_align(&self) -> Option<DirectAlign> {
+        match self {
+            File::Legacy(file) => file.direct_align(),
+            #[cfg(feature = "ringbahn")]
+            File::Ringbahn(file) => file.direct_align(),
+        }
+    }
+
+    // One read into `buf` at `offset`, handing it back with the count,
+    // which can be short.
+    pub async fn read_at<B: OwnedBuf>(&self, buf: B, offset: usize) -> (B, Result<usize, Error>) {
+        match self {
+            File::Legacy(file) => file.read_at(buf, offset).await,
+            #[cfg(feature = "ringbahn")]
+            File::Ringbahn(file) => file.read_at(buf, offset).await,
+        }
+    }
+
+    // One write of `buf` at `offset`, handing it back with the count,
+    // which can be short.
+    pub async fn write_at<B: OwnedBuf>(&self, buf: B, offset: usize) -> (B, Result<usize, Error>) {
+        match self {
+            File::Legacy(file) => file.write_at(buf, offset).await,
+            #[cfg(feature = "ringbahn")]
+            File::Ringbahn(file) => file.write_at(buf, offset).await,
+        }
+    }
+
+    // Flushes data and metadata to stable storage, like fsync(2).
+    pub async fn sync_all(&self) -> Result<(), Error> {
+        match self {
+            File::Legacy(file) => file.sync_all().await,
+            #[cfg(feature = "ringbahn")]
+            File::Ringbahn(file) => file.sync_all().await,
+        }
+    }
+
+    // Flushes data and only the metadata needed to read it back, like
+    // fdatasync(2).
+    pub async fn sync_data(&self) -> Result<(), Error> {
+        match self {
+            File::Legacy(file) => file.sync_data().await,
+            #[cfg(feature = "ringbahn")]
+            File::Ringbahn(file) => file.sync_data().await,
+        }
+    }
+
+    // sync_file_range(2) over `len` bytes at `offset`; a `len` of 0 runs
+    // to the end of the file. Not a durability barrier on its own.
+    #[cfg(target_os = "linux")]
+    pub async fn sync_range(&self, offset: u64, len: u64, flags: SyncRangeFlags) -> Result<(), Error> {
+        match self {
+            File::Legacy(file) => file.sync_range(offset, len, flags).await,
+            #[cfg(feature = "ringbahn")]
+            File::Ringbahn(file) => file.sync_range(offset, len, flags).await,
+        }
+    }
+
+    // Sends the bytes of this file in `range` to a socket without copying
+    // them through userspace. Stops early at the end of the file; returns
+    // how much was sent.
+    #[cfg(target_os = "linux")]
+    pub async fn send_to(&self, stream: &impl AsRawFd, range: Range<u64>) -> Result<u64, Error> {
+        let to = stream.as_raw_fd();
+        match self {
+            File::Legacy(file) => file.send_to(to, range).await,
+            #[cfg(feature = "ringbahn")]
+            File::Ringbahn(file) => file.send_to(to, range).await,
+        }
+    }
+
+    pub(crate) async fn read_pages(&self, buf: Buffer, high: usize, offset: usize) -> (Buffer, Result<usize, Error>) {
+        match self {
+            File::Legacy(file) => file.read_pages(buf, high, offset).await,
+            #[cfg(feature = "ringbahn")]
+            File::Ringbahn(file) => file.read_pages(buf, high, offset).await,
+        }
+    }
+
+    pub(crate) async fn write_pages(
+        &self, buf: Buffer, low: usize, high: usize, offset: usize, sync: bool
+    ) -> (Buffer, Result<usize, Error>) {
+        match self {
+            File::Legacy(file) => file.write_pages(buf, low, high, offset, sync).await,
+            #[cfg(feature = "ringbahn")]
+            File::Ringbahn(file) => file.write_pages(buf, low, high, offset, sync).await,
+        }
+    }
+
+    pub(crate) async fn write_all_pages(
+        &self, buf: Buffer, low: usize, high: usize, offset: usize, sync: bool
+    ) -> (Buffer, Result<(), Error>) {
+        match self {
+            File::Legacy(file) => file.write_all_pages(buf, low, high, offset, sync).await,
+            #[cfg(feature = "ringbahn")]
+            File::Ringbahn(file) => file.write_all_pages(buf, low, high, offset, sync).await,
+        }
+    }
+}
@@ -1,11 +1,19 @@
 use blocking::unblock;
 use crate::buffer::{Buffer, Readable, Writeable};
+use crate::direct::{self, DirectAlign};
+#[cfg(target_os = "linux")]
+use crate::fs::{self as backplane_fs, Statx, SyncRangeFlags};
+use crate::io::OwnedBuf;
+#[cfg(target_os = "linux")]
+use crate::splice;
 
 use std::cmp::min;
 use std::fs;
 use std::io::{self, Error, ErrorKind};
-use std::mem::{ManuallyDrop, replace};
-use std::path::PathBuf;
+use std::mem::ManuallyDrop;
+#[cfg(target_os = "linux")]
+use std::ops::Range;
+use std::path::Path;
 
 #[cfg(unix)]
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
@@ -22,44 +30,109 @@ use std::os::windows::io::{AsRawHandle, FromRawHandle, RawHandle};
 ))))]
 use std::os::unix::fs::FileExt;
 
-#[derive(Clone)]
+mod net;
+mod stream;
+mod unix;
+pub use net::{Incoming, RecvStream, TcpListener, TcpStream, UdpSocket};
+pub use stream::FileStream;
+pub use unix::{UnixDatagram, UnixListener, UnixStream};
+// These used to live here.
+pub use crate::paged::{ReadBuffer, WriteBuffer};
+
+#[derive(Clone, Default)]
 pub struct IO {}
 
 impl IO {
-    pub async fn open_file(path: PathBuf, opts: fs::OpenOptions) -> Result<File, Error> {
-        Ok(File(unblock(move || opts.open(path)).await?))
+    pub async fn create_file(&self, path: impl AsRef<Path>) -> Result<File, Error> {
+        let path = path.as_ref().to_owned();
+        Ok(File::new(unblock(move || fs::File::create(path)).await?))
+    }
+
+    pub async fn open_file(&self, path: impl AsRef<Path>, opts: &fs::OpenOptions) -> Result<File, Error> {
+        let path = path.as_ref().to_owned();
+        let opts = opts.clone();
+        Ok(File::new(unblock(move || opts.open(path)).await?))
+    }
+
+    pub fn from_file(&self, file: fs::File) -> File {
+        File::new(file)
+    }
+
+    #[cfg(target_os = "linux")]
+    pub async fn statx(&self, path: impl AsRef<Path>, mask: u32) -> Result<Statx, Error> {
+        backplane_fs::statx(path.as_ref().to_owned(), mask).await
+    }
+
+    #[cfg(target_os = "linux")]
+    pub async fn splice(
+        &self, from: RawFd, from_off: Option<u64>, to: RawFd, to_off: Option<u64>, len: usize
+    ) -> Result<usize, Error> {
+        unblock(move || splice::splice(from, from_off, to, to_off, len)).await
+    }
+
+    #[cfg(target_os = "linux")]
+    pub async fn tee(&self, from: RawFd, to: RawFd, len: usize) -> Result<usize, Error> {
+        unblock(move || splice::tee(from, to, len)).await
     }
 }
 
-pub struct File(fs::File);
+pub struct File(fs::File, Option<DirectAlign>);
 
-pub struct ReadBuffer {
-    buffer: Buffer,
-    high: usize,
-    low: usize,
+impl AsRawFd for File {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
 }
 
-impl ReadBuffer {
-    pub fn new() -> ReadBuffer {
-        ReadBuffer::from_buffer(Buffer::new())
+impl File {
+    fn new(file: fs::File) -> File {
+        let align = direct::alignment(file.as_raw_fd());
+        File(file, align)
+    }
+
+    // Some if the file was opened for direct I/O.
+    pub fn direct_align(&self) -> Option<DirectAlign> {
+        self.1
+    }
+
+    pub async fn read_at<B: OwnedBuf>(&self, buf: B, offset: usize) -> (B, Result<usize, Error>) {
+        let fd = self.0.as_raw_fd();
+        unblock(move || {
+            let mut buf = buf;
+            let read = read_at(fd, buf.as_mut(), offset);
+            (buf, read)
+        }).await
+    }
+
+    pub async fn write_at<B: OwnedBuf>(&self, buf: B, offset: usize) -> (B, Result<usize, Error>) {
+        let fd = self.0.as_raw_fd();
+        unblock(move || {
+            let wrote = write_at(fd, buf.as_ref(), offset);
+            (buf, wrote)
+        }).await
     }
- 
-    pub fn with_capacity(bytes: usize) -> Result<ReadBuffer, Error> {
-        Ok(ReadBuffer::from_buffer(Buffer::with_capacity(bytes)?))
+
+    pub async fn sync_all(&self) -> Result<(), Error> {
+        let fd = self.0.as_raw_fd();
+        unblock(move || ManuallyDrop::new(unsafe { fs::File::from_raw_fd(fd) }).sync_all()).await
     }
 
-    pub fn from_buffer(buffer: Buffer) -> ReadBuffer {
-        ReadBuffer { buffer, high: 0, low: 0 }
+    pub async fn sync_data(&self) -> Result<(), Error> {
+        let fd = self.0.as_raw_fd();
+        unblock(move || ManuallyDrop::new(unsafe { fs::File::from_raw_fd(fd) }).sync_data()).await
     }
 
-    pub fn clear(&mut self) {
-        self.high = 0;
-        self.low = 0;
+    #[cfg(target_os = "linux")]
+    pub async fn sync_range(&self, offset: u64, len: u64, flags: SyncRangeFlags) -> Result<(), Error> {
+        let fd = self.0.as_raw_fd();
+        unblock(move || backplane_fs::sync_range(fd, offset, len, flags)).await
     }
 
-    pub fn consume(&mut self, bytes: usize) {
-        self.low = min(self.low + bytes, self.high);
-        if self.low == self.high { self.clear(); }
+    #[cfg(target_os = "linux")]
+    pub async fn send_to(&self, to: RawFd, range: Range<u64>) -> Result<u64, Error> {
+        let fd = self.0.as_raw_fd();
+        let len = range.end.saturating_sub(range.start);
+        unblock(move || splice::sendfile(to, fd, range.start, len)).await
     }
 
     #[cfg(any(
@@ -70,30 +143,19 @@ impl ReadBuffer {
         target_os = "openbsd",
         target_os = "linux",
     ))]
-    pub async fn fill_at(&mut self, file: &File, offset: usize) -> Result<usize, Error> {
-        let buf = replace(&mut self.buffer, Buffer::new());
-        let high = self.high;
-        let fd = file.0.as_raw_fd();
-        let (buf2, read) = unblock(move || {
+    pub(crate) async fn read_pages(&self, buf: Buffer, high: usize, offset: usize) -> (Buffer, Result<usize, Error>) {
+        let fd = self.0.as_raw_fd();
+        unblock(move || {
             let mut buf = buf;
-            let writeable = Writeable::new(&mut buf, high);
-            let mut bufs = Vec::new();
-            for w in writeable {
-                match w {
-                    Ok(w) => { bufs.push(io::IoSliceMut::new(&mut w[..])); }
-                    Err(e) => { return (buf, Err(e)); }
+            let read = match Writeable::spare(&mut buf, high) {
+                Ok(spare) => {
+                    let mut bufs: Vec<io::IoSliceMut> = spare.into_iter().map(io::IoSliceMut::new).collect();
+                    read_vectored_at(fd, &mut bufs[..], offset)
                 }
-            }
-            match read_vectored_at(fd, &mut bufs[..], offset) {
-                Ok(count) => (buf, Ok(count)),
-                Err(e) => (buf, Err(e)),
-            }
-        }).await;
-        #[allow(unused_must_use)]
-        { replace(&mut self.buffer, buf2); }
-        let read = read?;
-        self.high += read;
-        Ok(read)
+                Err(e) => Err(e),
+            };
+            (buf, read)
+        }).await
     }
 
     #[cfg(all(unix,not(any(
@@ -104,96 +166,18 @@ impl ReadBuffer {
         target_os = "openbsd",
         target_os = "linux",
     ))))]
-    pub async fn fill_at(&mut self, file: &File, pos: usize) -> Result<usize, Error> {
-        let buf = replace(&mut self.buffer, Buffer::new());
-        let high = self.high;
-        let fd = file.0.as_raw_fd();
-        let (buf2, read) = unblock(move || {
+    pub(crate) async fn read_pages(&self, buf: Buffer, high: usize, pos: usize) -> (Buffer, Result<usize, Error>) {
+        let fd = self.0.as_raw_fd();
+        unblock(move || {
             let mut buf = buf;
             let file = ManuallyDrop::new(unsafe { fs::File::from_raw_fd(fd) });
             let mut writeable = Writeable::new(&mut buf, high);
-            match writeable.next_slice() {
-                Ok(w) => {
-                    match file.read_at(w, pos as u64) {
-                        Ok(count) => (buf, Ok(count)),
-                        Err(err) => (buf, Err(err)),
-                    }
-                }
-                Err(e) => (buf, Err(e)),
-            }
-        }).await;
-        #[allow(unused_must_use)]
-        { replace(&mut self.buffer, buf2); }
-        let read = read?;
-        self.high += read;
-        Ok(read)
-    }
-}
-
-pub struct WriteBuffer {
-    buffer: Buffer,
-    high: usize,
-    low: usize,
-}
-
-impl WriteBuffer {
-    pub fn new() -> WriteBuffer {
-        WriteBuffer::from_buffer(Buffer::new())
-    }
- 
-    pub fn with_capacity(bytes: usize) -> Result<WriteBuffer, Error> {
-        Ok(WriteBuffer::from_buffer(Buffer::with_capacity(bytes)?))
-    }
-
-    pub fn from_buffer(buffer: Buffer) -> WriteBuffer {
-        WriteBuffer { buffer, high: 0, low: 0 }
-    }
-
-    pub fn clear(&mut self) {
-        self.high = 0;
-        self.low = 0;
-    }
-
-    pub fn buffer(&mut self, buf: &[u8]) -> Result<usize, Error> {
-        if buf.is_empty() { return Ok(0) }
-        let mut buf = buf;
-        let mut wrote: usize = 0;
-        let mut wr = Writeable::new(&mut self.buffer, self.high);
-        let mut w = Some(wr.next_slice()?);
-        loop {
-            let bl = buf.len();
-            let w2 = w.take().unwrap();
-            let wl = w2.len();
-            if bl > wl {
-                {
-                    let w3 = w2;
-                    w3.copy_from_slice(&buf[..wl]);
-                }
-                buf = &buf[wl..];
-                wrote += wl;
-                match wr.next_slice() {
-                    Ok(slice) => { w = Some(slice); }
-                    Err(e) => {
-                        self.high += wrote;
-                        return Err(e);
-                    }
-                }
-            } else {
-                if wl > bl {
-                    (&mut w2[..bl]).copy_from_slice(buf);
-                } else {
-                    w2.copy_from_slice(buf);
-                }
-                wrote += bl;
-                break;
-            }
-        }
-        self.high += wrote;
-        Ok(wrote)
-    }
-
-    pub fn len(&self) -> usize {
-        self.high - self.low
+            let read = match writeable.next_slice() {
+                Ok(w) => file.read_at(w, pos as u64),
+                Err(e) => Err(e),
+            };
+            (buf, read)
+        }).await
     }
 
     // when pwritev is available, we can make fewer syscalls!
@@ -205,35 +189,55 @@ impl WriteBuffer {
         target_os = "openbsd",
         target_os = "linux",
     ))]
-    pub async fn write_at(&mut self, file: &File, offset: usize, sync: bool) -> Result<(), Error> {
-        let buf = replace(&mut self.buffer, Buffer::new());
-        let high = self.high;
-        let low = self.low;
-        let fd = file.0.as_raw_fd();
-        let (buf2, count) = unblock(move || {
+    pub(crate) async fn write_pages(
+        &self, buf: Buffer, low: usize, high: usize, offset: usize, sync: bool
+    ) -> (Buffer, Result<usize, Error>) {
+        let fd = self.0.as_raw_fd();
+        unblock(move || {
             let mut buf = buf;
             let readable = Readable::new(&mut buf, low, high - low);
-            let mut bufs: Vec<io::IoSlice> = readable.map(|s| io::IoSlice::new(&s[..])).collect();
-            if bufs.is_empty() {
-                Ok((buf, 0))
+            let bufs: Vec<io::IoSlice> = readable.map(|s| io::IoSlice::new(&s[..])).collect();
+            let count = if bufs.is_empty() {
+                Ok(0)
             } else {
-                let count = write_vectored_at(fd, bufs.as_mut_slice(), offset)?;
-                if sync {
-                    ManuallyDrop::new(unsafe { fs::File::from_raw_fd(fd) }).sync_data()?;
-                }
-                Ok::<_, Error>((buf, count))
-            }
-        }).await?;
-        #[allow(unused_must_use)]
-        { replace(&mut self.buffer, buf2); }
-        self.low += count;
-        if self.low == self.high {
-            self.clear();
-        }
-        Ok(())
+                write_vectored_at(fd, &bufs[..], offset).and_then(|count| {
+                    if sync {
+                        ManuallyDrop::new(unsafe { fs::File::from_raw_fd(fd) }).sync_data()?;
+                    }
+                    Ok(count)
+                })
+            };
+            (buf, count)
+        }).await
+    }
+
+    #[cfg(all(unix,not(any(
+        target_os = "dragonfly",
+        target_os = "freebsd",
+        target_os = "macos",
+        target_os = "netbsd",
+        target_os = "openbsd",
+        target_os = "linux",
+    ))))]
+    pub(crate) async fn write_pages(
+        &self, buf: Buffer, low: usize, high: usize, offset: usize, sync: bool
+    ) -> (Buffer, Result<usize, Error>) {
+        let fd = self.0.as_raw_fd();
+        unblock(move || {
+            let mut buf = buf;
+            let file = ManuallyDrop::new(unsafe { fs::File::from_raw_fd(fd) });
+            let mut readable = Readable::new(&mut buf, low, high - low);
+            let count = match readable.next() {
+                Some(r) => file.write_at(r, offset as u64).and_then(|count| {
+                    if sync { file.sync_data()?; }
+                    Ok(count)
+                }),
+                None => Ok(0),
+            };
+            (buf, count)
+        }).await
     }
 
-    // when pwritev is available, we can make fewer syscalls!
     #[cfg(any(
         target_os = "dragonfly",
         target_os = "freebsd",
@@ -242,64 +246,50 @@ impl WriteBuffer {
         target_os = "openbsd",
         target_os = "linux",
     ))]
-    pub async fn write_all_at(&mut self, file: &File, offset: usize, sync: bool) -> Result<(), Error> {
-        let buf = replace(&mut self.buffer, Buffer::new());
-        let high = self.high;
-        let low = self.low;
-        let fd = file.0.as_raw_fd();
-        let buf2 = unblock(move || {
+    pub(crate) async fn write_all_pages(
+        &self, buf: Buffer, low: usize, high: usize, offset: usize, sync: bool
+    ) -> (Buffer, Result<(), Error>) {
+        let fd = self.0.as_raw_fd();
+        unblock(move || {
             let mut buf = buf;
             let readable = Readable::new(&mut buf, low, high - low);
             let mut bufs: Vec<io::IoSlice> = readable.map(|s| io::IoSlice::new(&s[..])).collect();
-            write_all_vectored_at(fd, bufs.as_mut_slice(), offset)?;
-            if sync {
-                ManuallyDrop::new(unsafe { fs::File::from_raw_fd(fd) }).sync_data()?;
-            }
-            Ok::<_, Error>(buf)
-        }).await?;
-        #[allow(unused_must_use)]
-        { replace(&mut self.buffer, buf2); }
-        self.clear();
-        Ok(())
+            let res = write_all_vectored_at(fd, bufs.as_mut_slice(), offset).and_then(|_| {
+                if sync {
+                    ManuallyDrop::new(unsafe { fs::File::from_raw_fd(fd) }).sync_data()?;
+                }
+                Ok(())
+            });
+            (buf, res)
+        }).await
     }
 
-    #[cfg(all(
-        unix,
-        not(any(
-            target_os = "dragonfly",
-            target_os = "freebsd",
-            target_os = "macos",
-            target_os = "netbsd",
-            target_os = "openbsd",
-            target_os = "linux",
-        ))))]
-    pub async fn write_all_at(mut self, file: &fs::File, offset: usize, sync: bool) -> Result<(), Error> {
-        let buf = replace(&mut self.buffer, Buffer::new());
-        let high = self.high;
-        let low = self.low;
-        let fd = file.as_raw_fd();
-        let buf2 = unblock(move || {
+    #[cfg(all(unix,not(any(
+        target_os = "dragonfly",
+        target_os = "freebsd",
+        target_os = "macos",
+        target_os = "netbsd",
+        target_os = "openbsd",
+        target_os = "linux",
+    ))))]
+    pub(crate) async fn write_all_pages(
+        &self, buf: Buffer, low: usize, high: usize, offset: usize, sync: bool
+    ) -> (Buffer, Result<(), Error>) {
+        let fd = self.0.as_raw_fd();
+        unblock(move || {
             let mut buf = buf;
             let file = ManuallyDrop::new(unsafe { fs::File::from_raw_fd(fd) });
             let mut wrote: usize = 0;
-            let mut readable = Readable::new(&mut buf, low, high - low);
-            for r in readable {
-                loop {
-                    if let Err(err) = file.write_all_at(r, (offset + low) as u64) {
-                        if err.kind() != ErrorKind::Interrupted { return Err(err); }
-                    } else {
-                        low += r.len();
-                        break;
-                    }
+            let res = (|| {
+                for r in Readable::new(&mut buf, low, high - low) {
+                    file.write_all_at(r, (offset + wrote) as u64)?;
+                    wrote += r.len();
                 }
-            }
-            if sync { file.sync_data()?; }
-            Ok(buf)
-        }).await?;
-        #[allow(unused_must_use)]
-        { replace(&mut self.buffer, buf2); }
-        self.clear();
-        Ok(())
+                if sync { file.sync_data()?; }
+                Ok::<_, Error>(())
+            })();
+            (buf, res)
+        }).await
     }
 }
 
@@ -317,6 +307,24 @@ const MAX_IOV: usize = libc::IOV_MAX as usize;
 #[cfg(target_os = "linux")]
 const MAX_IOV: usize = libc::UIO_MAXIOV as usize;
 
+fn read_at(fd: RawFd, buf: &mut [u8], offset: usize) -> Result<usize, Error> {
+    let ret = unsafe { libc::pread(fd, buf.as_mut_ptr().cast(), buf.len(), offset as libc::off_t) };
+    if ret as isize == -1 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+fn write_at(fd: RawFd, buf: &[u8], offset: usize) -> Result<usize, Error> {
+    let ret = unsafe { libc::pwrite(fd, buf.as_ptr().cast(), buf.len(), offset as libc::off_t) };
+    if ret as isize == -1 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(ret as usize)
+    }
+}
+
 #[cfg(any(
     target_os = "dragonfly",
     target_os = "freebsd",
@@ -0,0 +1,355 @@
+// Sockets on the threadpool. Each operation parks a pool thread until the
+// socket is ready, which is fine for a handful of connections and is what
+// we fall back to when io_uring isn't around.
+
+use blocking::unblock;
+use crate::buffer::{Buffer, Readable, Writeable};
+#[cfg(target_os = "linux")]
+use crate::buffer::page_size;
+use crate::io::OwnedBuf;
+#[cfg(target_os = "linux")]
+use crate::net::SockAddr;
+use crate::net::{Datagram, Datagrams};
+use futures_core::Stream;
+use super::IO;
+
+use std::future::Future;
+use std::io::{self, Error, Read, Write};
+use std::mem::ManuallyDrop;
+use std::net::{self, Shutdown, SocketAddr};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+pub struct TcpListener(net::TcpListener);
+
+pub struct TcpStream(net::TcpStream);
+
+pub struct UdpSocket(net::UdpSocket);
+
+// One accept or receive at a time, over and over.
+pub struct Incoming<'a> {
+    listener: &'a TcpListener,
+    accept: Option<Op<'a, Result<(TcpStream, SocketAddr), Error>>>,
+}
+
+pub struct RecvStream<'a> {
+    stream: &'a TcpStream,
+    recv: Option<Op<'a, (Buffer, Result<usize, Error>)>>,
+}
+
+type Op<'a, T> = Pin<Box<dyn Future<Output = T> + 'a>>;
+
+// Borrows the socket for the duration of a blocking call without taking
+// ownership of the fd, like the file operations do.
+pub(super) fn borrow<T: FromRawFd>(fd: RawFd) -> ManuallyDrop<T> {
+    ManuallyDrop::new(unsafe { T::from_raw_fd(fd) })
+}
+
+impl IO {
+    pub async fn bind_tcp(&self, addr: SocketAddr) -> Result<TcpListener, Error> {
+        Ok(TcpListener(unblock(move || net::TcpListener::bind(addr)).await?))
+    }
+
+    pub async fn connect_tcp(&self, addr: SocketAddr) -> Result<TcpStream, Error> {
+        Ok(TcpStream(unblock(move || net::TcpStream::connect(addr)).await?))
+    }
+
+    pub async fn bind_udp(&self, addr: SocketAddr) -> Result<UdpSocket, Error> {
+        Ok(UdpSocket(unblock(move || net::UdpSocket::bind(addr)).await?))
+    }
+}
+
+impl AsRawFd for TcpListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl TcpListener {
+    pub async fn accept(&self) -> Result<(TcpStream, SocketAddr), Error> {
+        let fd = self.0.as_raw_fd();
+        let (stream, addr) = unblock(move || borrow::<net::TcpListener>(fd).accept()).await?;
+        Ok((TcpStream(stream), addr))
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        self.0.local_addr()
+    }
+
+    // Every connection as it's accepted. Never ends.
+    pub fn incoming(&self) -> Incoming<'_> {
+        Incoming { listener: self, accept: None }
+    }
+}
+
+impl<'a> Stream for Incoming<'a> {
+    type Item = Result<(TcpStream, SocketAddr), Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let listener = self.listener;
+        let accept = self.accept.get_or_insert_with(|| Box::pin(listener.accept()));
+        match accept.as_mut().poll(cx) {
+            Poll::Ready(accepted) => {
+                self.accept = None;
+                Poll::Ready(Some(accepted))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl AsRawFd for TcpStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl TcpStream {
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        self.0.local_addr()
+    }
+
+    pub fn peer_addr(&self) -> Result<SocketAddr, Error> {
+        self.0.peer_addr()
+    }
+
+    pub fn set_nodelay(&self, nodelay: bool) -> Result<(), Error> {
+        self.0.set_nodelay(nodelay)
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> Result<(), Error> {
+        self.0.shutdown(how)
+    }
+
+    pub async fn recv<B: OwnedBuf>(&self, buf: B) -> (B, Result<usize, Error>) {
+        let fd = self.0.as_raw_fd();
+        unblock(move || {
+            let mut buf = buf;
+            let read = (&*borrow::<net::TcpStream>(fd)).read(buf.as_mut());
+            (buf, read)
+        }).await
+    }
+
+    pub async fn send<B: OwnedBuf>(&self, buf: B) -> (B, Result<usize, Error>) {
+        let fd = self.0.as_raw_fd();
+        unblock(move || {
+            let wrote = (&*borrow::<net::TcpStream>(fd)).write(buf.as_ref());
+            (buf, wrote)
+        }).await
+    }
+
+    // Whatever arrives, a page at a time from the buffer pool, until the
+    // peer shuts down its side.
+    pub fn recv_stream(&self) -> RecvStream<'_> {
+        RecvStream { stream: self, recv: None }
+    }
+
+    pub(crate) async fn recv_pages(&self, buf: Buffer, high: usize) -> (Buffer, Result<usize, Error>) {
+        let fd = self.0.as_raw_fd();
+        unblock(move || {
+            let mut buf = buf;
+            let read = match Writeable::spare(&mut buf, high) {
+                Ok(spare) => {
+                    // std caps the count at the platform's IOV_MAX for us.
+                    let mut bufs: Vec<io::IoSliceMut> = spare.into_iter().map(io::IoSliceMut::new).collect();
+                    (&*borrow::<net::TcpStream>(fd)).read_vectored(&mut bufs[..])
+                }
+                Err(e) => Err(e),
+            };
+            (buf, read)
+        }).await
+    }
+
+    pub(crate) async fn send_pages(&self, buf: Buffer, low: usize, high: usize) -> (Buffer, Result<usize, Error>) {
+        let fd = self.0.as_raw_fd();
+        unblock(move || {
+            let mut buf = buf;
+            let readable = Readable::new(&mut buf, low, high - low);
+            let bufs: Vec<io::IoSlice> = readable.map(|s| io::IoSlice::new(&s[..])).collect();
+            let wrote = (&*borrow::<net::TcpStream>(fd)).write_vectored(&bufs[..]);
+            (buf, wrote)
+        }).await
+    }
+}
+
+impl<'a> Stream for RecvStream<'a> {
+    // A page and how much of it was filled.
+    type Item = Result<(Buffer, usize), Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.recv.is_none() {
+            let page = match Buffer::with_pages(1) {
+                Ok(page) => page,
+                Err(e) => { return Poll::Ready(Some(Err(e))); }
+            };
+            let stream = self.stream;
+            self.recv = Some(Box::pin(stream.recv_pages(page, 0)));
+        }
+        let (page, read) = match self.recv.as_mut().unwrap().as_mut().poll(cx) {
+            Poll::Ready(ret) => ret,
+            Poll::Pending => { return Poll::Pending; }
+        };
+        self.recv = None;
+        match read {
+            Ok(0) => Poll::Ready(None),
+            Ok(read) => Poll::Ready(Some(Ok((page, read)))),
+            Err(e) => Poll::Ready(Some(Err(e))),
+        }
+    }
+}
+
+impl AsRawFd for UdpSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl UdpSocket {
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        self.0.local_addr()
+    }
+
+    pub async fn send_to<B: OwnedBuf>(&self, buf: B, addr: SocketAddr) -> (B, Result<usize, Error>) {
+        let fd = self.0.as_raw_fd();
+        unblock(move || {
+            let wrote = borrow::<net::UdpSocket>(fd).send_to(buf.as_ref(), addr);
+            (buf, wrote)
+        }).await
+    }
+
+    pub async fn recv_from<B: OwnedBuf>(&self, buf: B) -> (B, Result<(usize, SocketAddr), Error>) {
+        let fd = self.0.as_raw_fd();
+        unblock(move || {
+            let mut buf = buf;
+            let read = borrow::<net::UdpSocket>(fd).recv_from(buf.as_mut());
+            (buf, read)
+        }).await
+    }
+
+    #[cfg(target_os = "linux")]
+    pub(crate) async fn send_batch(&self, batch: Datagrams) -> (Datagrams, Result<usize, Error>) {
+        let fd = self.0.as_raw_fd();
+        unblock(move || {
+            let mut batch = batch;
+            let sent = sendmmsg(fd, &mut batch);
+            (batch, sent)
+        }).await
+    }
+
+    // No sendmmsg, so one syscall per datagram.
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) async fn send_batch(&self, batch: Datagrams) -> (Datagrams, Result<usize, Error>) {
+        let fd = self.0.as_raw_fd();
+        unblock(move || {
+            let socket = borrow::<net::UdpSocket>(fd);
+            let mut sent = 0;
+            for (data, datagram) in batch.iter() {
+                match socket.send_to(data, datagram.addr()) {
+                    Ok(_) => { sent += 1; }
+                    Err(e) if sent == 0 => { return (batch, Err(e)); }
+                    Err(_) => { break; }
+                }
+            }
+            (batch, Ok(sent))
+        }).await
+    }
+
+    #[cfg(target_os = "linux")]
+    pub(crate) async fn recv_batch(&self, batch: Datagrams, max: usize) -> (Datagrams, Result<usize, Error>) {
+        let fd = self.0.as_raw_fd();
+        unblock(move || {
+            let mut batch = batch;
+            let read = recvmmsg(fd, &mut batch, max);
+            (batch, read)
+        }).await
+    }
+
+    // No recvmmsg, so we settle for the one datagram we waited for.
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) async fn recv_batch(&self, batch: Datagrams, max: usize) -> (Datagrams, Result<usize, Error>) {
+        let fd = self.0.as_raw_fd();
+        unblock(move || {
+            let mut batch = batch;
+            batch.clear();
+            if max == 0 { return (batch, Ok(0)); }
+            let page = match Writeable::new(&mut batch.buffer, 0).next_slice() {
+                Ok(page) => page,
+                Err(e) => { return (batch, Err(e)); }
+            };
+            let read = borrow::<net::UdpSocket>(fd).recv_from(page).map(|(len, addr)| {
+                batch.datagrams.push(Datagram { len, addr, truncated: false });
+                1
+            });
+            (batch, read)
+        }).await
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn sendmmsg(fd: RawFd, batch: &mut Datagrams) -> Result<usize, Error> {
+    if batch.is_empty() { return Ok(0); }
+    let count = batch.len();
+    let mut addrs: Vec<SockAddr> = batch.datagrams.iter().map(|d| SockAddr::from_addr(&d.addr)).collect();
+    let mut iovecs: Vec<libc::iovec> = Writeable::spare(&mut batch.buffer, 0)?
+        .into_iter()
+        .zip(batch.datagrams.iter())
+        .map(|(page, d)| libc::iovec { iov_base: page.as_mut_ptr().cast(), iov_len: d.len })
+        .collect();
+    let mut msgs = mmsghdrs(&mut addrs, &mut iovecs);
+    let mut sent = 0;
+    while sent < count {
+        let ret = unsafe { libc::sendmmsg(fd, msgs[sent..].as_mut_ptr(), (count - sent) as libc::c_uint, 0) };
+        if ret == -1 {
+            let e = Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted { continue; }
+            return if sent == 0 { Err(e) } else { Ok(sent) };
+        }
+        sent += ret as usize;
+    }
+    Ok(sent)
+}
+
+#[cfg(target_os = "linux")]
+fn recvmmsg(fd: RawFd, batch: &mut Datagrams, max: usize) -> Result<usize, Error> {
+    batch.clear();
+    if max == 0 { return Ok(0); }
+    batch.buffer.reserve(max * page_size())?;
+    let mut addrs: Vec<SockAddr> = (0..max).map(|_| SockAddr::empty()).collect();
+    let mut iovecs: Vec<libc::iovec> = Writeable::spare(&mut batch.buffer, 0)?
+        .into_iter()
+        .take(max)
+        .map(|page| libc::iovec { iov_base: page.as_mut_ptr().cast(), iov_len: page.len() })
+        .collect();
+    let mut msgs = mmsghdrs(&mut addrs, &mut iovecs);
+    // Block for the first datagram, then take whatever else is queued.
+    let ret = loop {
+        let ret = unsafe {
+            libc::recvmmsg(fd, msgs.as_mut_ptr(), max as libc::c_uint, libc::MSG_WAITFORONE, std::ptr::null_mut())
+        };
+        if ret != -1 { break ret as usize; }
+        let e = Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted { return Err(e); }
+    };
+    for (msg, addr) in msgs[..ret].iter().zip(addrs.iter_mut()) {
+        addr.len = msg.msg_hdr.msg_namelen;
+        batch.datagrams.push(Datagram {
+            len: msg.msg_len as usize,
+            addr: addr.to_addr()?,
+            truncated: msg.msg_hdr.msg_flags & libc::MSG_TRUNC != 0,
+        });
+    }
+    Ok(ret)
+}
+
+#[cfg(target_os = "linux")]
+fn mmsghdrs(addrs: &mut [SockAddr], iovecs: &mut [libc::iovec]) -> Vec<libc::mmsghdr> {
+    addrs.iter_mut().zip(iovecs.iter_mut()).map(|(addr, iov)| {
+        let mut msg: libc::mmsghdr = unsafe { std::mem::zeroed() };
+        msg.msg_hdr.msg_name = (&mut addr.storage as *mut libc::sockaddr_storage).cast();
+        msg.msg_hdr.msg_namelen = addr.len;
+        msg.msg_hdr.msg_iov = iov;
+        msg.msg_hdr.msg_iovlen = 1;
+        msg
+    }).collect()
+}
@@ -0,0 +1,259 @@
+use blocking::unblock;
+use crate::buffer::{page_size, Buffer};
+use crate::paged::{ReadBuffer, WriteBuffer};
+use futures_io::{AsyncBufRead, AsyncRead, AsyncSeek, AsyncWrite};
+use super::File;
+
+use std::cmp::min;
+use std::future::Future;
+use std::io::{Error, ErrorKind, SeekFrom};
+use std::mem::replace;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+// Returns early unless the poll is Ready(Ok(_)).
+macro_rules! ready {
+    ($e:expr) => {
+        match $e {
+            Poll::Ready(Ok(t)) => t,
+            Poll::Ready(Err(e)) => { return Poll::Ready(Err(e)); }
+            Poll::Pending => { return Poll::Pending; }
+        }
+    };
+}
+
+const DEFAULT_CAPACITY: usize = 64 * 1024;
+
+type Op<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+enum State {
+    Idle,
+    Filling(Op<(Buffer, Result<usize, Error>)>),
+    Flushing(Op<(Buffer, Result<(), Error>)>),
+    // Finding the end of the file for SeekFrom::End(offset).
+    Sizing(Op<Result<u64, Error>>, i64),
+}
+
+// A cursor over a File for the sequential I/O traits, so it can be handed
+// to codecs and the like. Reads go through a ReadBuffer, writes are
+// gathered in a WriteBuffer and go out when it fills up, on flush or
+// before anything that needs to see them (reading, seeking).
+pub struct FileStream {
+    file: Arc<File>,
+    capacity: usize,
+    // The cursor. The read buffer holds the bytes starting here.
+    pos: u64,
+    read: ReadBuffer,
+    // The write buffer holds the bytes starting here, up to the cursor.
+    write_pos: u64,
+    write: WriteBuffer,
+    state: State,
+    // The last fill hit the end of the file. It stays that way until a
+    // seek or write, so seek to pick up anything appended since.
+    eof: bool,
+}
+
+impl FileStream {
+    pub fn new(file: File) -> FileStream {
+        FileStream::with_capacity(file, DEFAULT_CAPACITY)
+    }
+
+    pub fn with_capacity(file: File, bytes: usize) -> FileStream {
+        FileStream {
+            file: Arc::new(file),
+            capacity: bytes.max(page_size()),
+            pos: 0,
+            read: ReadBuffer::new(),
+            write_pos: 0,
+            write: WriteBuffer::new(),
+            state: State::Idle,
+            eof: false,
+        }
+    }
+
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    // Finishes whatever fill or flush is in flight.
+    fn poll_state(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        match &mut self.state {
+            State::Idle | State::Sizing(..) => {}
+            State::Filling(op) => {
+                let (buf, read) = match op.as_mut().poll(cx) {
+                    Poll::Ready(ret) => ret,
+                    Poll::Pending => { return Poll::Pending; }
+                };
+                self.state = State::Idle;
+                self.read.buffer = buf;
+                let read = read?;
+                self.read.high += read;
+                self.eof = read == 0;
+            }
+            State::Flushing(op) => {
+                let (buf, res) = match op.as_mut().poll(cx) {
+                    Poll::Ready(ret) => ret,
+                    Poll::Pending => { return Poll::Pending; }
+                };
+                self.state = State::Idle;
+                self.write.buffer = buf;
+                res?;
+                self.write.clear();
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_flush_writes(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        ready!(self.poll_state(cx));
+        if self.write.len() > 0 {
+            let buf = replace(&mut self.write.buffer, Buffer::new());
+            let (low, high) = (self.write.low, self.write.high);
+            let (file, offset) = (self.file.clone(), self.write_pos as usize);
+            self.state = State::Flushing(Box::pin(async move {
+                file.write_all_pages(buf, low, high, offset, false).await
+            }));
+            return self.poll_state(cx);
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn seek_to(&mut self, target: u64) -> u64 {
+        self.eof = false;
+        let buffered = (self.read.high - self.read.low) as u64;
+        if target >= self.pos && target < self.pos + buffered {
+            self.read.consume((target - self.pos) as usize);
+        } else {
+            self.read.clear();
+        }
+        self.pos = target;
+        target
+    }
+}
+
+fn offset(base: u64, by: i64) -> Result<u64, Error> {
+    let target = if by < 0 { base.checked_sub(by.wrapping_neg() as u64) } else { base.checked_add(by as u64) };
+    target.ok_or_else(|| Error::new(ErrorKind::InvalidInput, "invalid seek to a negative or overflowing position"))
+}
+
+impl AsyncBufRead for FileStream {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<&[u8], Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_state(cx));
+        if this.read.low == this.read.high {
+            if this.eof { return Poll::Ready(Ok(&[])); }
+            // Anything we've written has to land before we read past it.
+            ready!(this.poll_flush_writes(cx));
+            this.read.clear();
+            this.read.buffer.reserve(this.capacity)?;
+            let buf = replace(&mut this.read.buffer, Buffer::new());
+            let (file, offset) = (this.file.clone(), this.pos as usize);
+            this.state = State::Filling(Box::pin(async move { file.read_pages(buf, 0, offset).await }));
+            ready!(this.poll_state(cx));
+        }
+        let len = this.read.high - this.read.low;
+        Poll::Ready(Ok(this.read.buffer.read_first(this.read.low, len).unwrap_or(&[])))
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        let this = self.get_mut();
+        let amt = min(amt, this.read.high - this.read.low);
+        this.read.consume(amt);
+        this.pos += amt as u64;
+    }
+}
+
+impl AsyncRead for FileStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<Result<usize, Error>> {
+        let chunk = ready!(self.as_mut().poll_fill_buf(cx));
+        let count = min(chunk.len(), buf.len());
+        buf[..count].copy_from_slice(&chunk[..count]);
+        self.consume(count);
+        Poll::Ready(Ok(count))
+    }
+}
+
+impl AsyncWrite for FileStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_state(cx));
+        if this.write.len() >= this.capacity {
+            ready!(this.poll_flush_writes(cx));
+        }
+        // Whatever we had buffered for reading is stale now.
+        this.read.clear();
+        this.eof = false;
+        if this.write.len() == 0 {
+            this.write.clear();
+            this.write_pos = this.pos;
+        }
+        let count = min(buf.len(), this.capacity - this.write.len());
+        let count = this.write.buffer(&buf[..count])?;
+        this.pos += count as u64;
+        Poll::Ready(Ok(count))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.get_mut().poll_flush_writes(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.poll_flush(cx)
+    }
+}
+
+impl AsyncSeek for FileStream {
+    fn poll_seek(self: Pin<&mut Self>, cx: &mut Context<'_>, pos: SeekFrom) -> Poll<Result<u64, Error>> {
+        let this = self.get_mut();
+        if let State::Sizing(..) = this.state {} else {
+            ready!(this.poll_flush_writes(cx));
+            match pos {
+                SeekFrom::Start(target) => { return Poll::Ready(Ok(this.seek_to(target))); }
+                SeekFrom::Current(by) => {
+                    return Poll::Ready(offset(this.pos, by).map(|target| this.seek_to(target)));
+                }
+                SeekFrom::End(by) => {
+                    let file = this.file.clone();
+                    this.state = State::Sizing(Box::pin(unblock(move || Ok(file.0.metadata()?.len()))), by);
+                }
+            }
+        }
+        if let State::Sizing(op, by) = &mut this.state {
+            let by = *by;
+            let len = match op.as_mut().poll(cx) {
+                Poll::Ready(len) => len,
+                Poll::Pending => { return Poll::Pending; }
+            };
+            this.state = State::Idle;
+            return Poll::Ready(len.and_then(|len| offset(len, by)).map(|target| this.seek_to(target)));
+        }
+        unreachable!()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_lite::future::block_on;
+    use futures_lite::io::{AsyncBufReadExt, AsyncReadExt};
+    use super::FileStream;
+    use crate::legacy::IO;
+
+    use std::fs;
+
+    #[test]
+    fn reads_to_eof() {
+        let path = std::env::temp_dir().join(format!("io-backplane-stream-{}", std::process::id()));
+        fs::write(&path, b"hello").unwrap();
+        block_on(async {
+            let file = IO::default().open_file(&path, fs::OpenOptions::new().read(true)).await.unwrap();
+            let mut stream = FileStream::new(file);
+            let mut out = Vec::new();
+            assert_eq!(stream.read_to_end(&mut out).await.unwrap(), 5);
+            assert_eq!(out, b"hello");
+            assert_eq!(stream.position(), 5);
+            assert_eq!(stream.fill_buf().await.unwrap(), b"");
+        });
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,198 @@
+use blocking::unblock;
+use crate::io::OwnedBuf;
+use crate::unix::{received_fds, Control};
+use super::net::borrow;
+use super::IO;
+
+use std::fs;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::mem::zeroed;
+use std::net::Shutdown;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net;
+use std::path::Path;
+
+// Linux only: no SIGPIPE from a closed peer, and received descriptors
+// aren't leaked into exec'd children.
+#[cfg(target_os = "linux")]
+const SEND_FLAGS: libc::c_int = libc::MSG_NOSIGNAL;
+#[cfg(not(target_os = "linux"))]
+const SEND_FLAGS: libc::c_int = 0;
+#[cfg(target_os = "linux")]
+const RECV_FLAGS: libc::c_int = libc::MSG_CMSG_CLOEXEC;
+#[cfg(not(target_os = "linux"))]
+const RECV_FLAGS: libc::c_int = 0;
+
+pub struct UnixListener(net::UnixListener);
+
+pub struct UnixStream(net::UnixStream);
+
+pub struct UnixDatagram(net::UnixDatagram);
+
+impl IO {
+    pub async fn bind_unix(&self, path: impl AsRef<Path>) -> Result<UnixListener, Error> {
+        let path = path.as_ref().to_owned();
+        Ok(UnixListener(unblock(move || net::UnixListener::bind(path)).await?))
+    }
+
+    pub async fn connect_unix(&self, path: impl AsRef<Path>) -> Result<UnixStream, Error> {
+        let path = path.as_ref().to_owned();
+        Ok(UnixStream(unblock(move || net::UnixStream::connect(path)).await?))
+    }
+
+    pub fn unix_pair(&self) -> Result<(UnixStream, UnixStream), Error> {
+        let (a, b) = net::UnixStream::pair()?;
+        Ok((UnixStream(a), UnixStream(b)))
+    }
+
+    pub async fn bind_unix_datagram(&self, path: impl AsRef<Path>) -> Result<UnixDatagram, Error> {
+        let path = path.as_ref().to_owned();
+        Ok(UnixDatagram(unblock(move || net::UnixDatagram::bind(path)).await?))
+    }
+
+    pub fn unix_datagram_pair(&self) -> Result<(UnixDatagram, UnixDatagram), Error> {
+        let (a, b) = net::UnixDatagram::pair()?;
+        Ok((UnixDatagram(a), UnixDatagram(b)))
+    }
+}
+
+// sendmsg(2) with the descriptors as SCM_RIGHTS. Blocking.
+fn send_fds(fd: RawFd, buf: &[u8], fds: &[RawFd]) -> Result<usize, Error> {
+    let mut control = Control::rights(fds)?;
+    let mut iov = libc::iovec { iov_base: buf.as_ptr() as *mut libc::c_void, iov_len: buf.len() };
+    let mut msg: libc::msghdr = unsafe { zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    control.attach(&mut msg);
+    loop {
+        let ret = unsafe { libc::sendmsg(fd, &msg, SEND_FLAGS) };
+        if ret != -1 { return Ok(ret as usize); }
+        let e = Error::last_os_error();
+        if e.kind() != ErrorKind::Interrupted { return Err(e); }
+    }
+}
+
+// recvmsg(2) with room for `max` descriptors. Blocking.
+fn recv_fds(fd: RawFd, buf: &mut [u8], max: usize) -> Result<(usize, Vec<fs::File>), Error> {
+    let mut control = Control::with_room(max);
+    let mut iov = libc::iovec { iov_base: buf.as_mut_ptr().cast(), iov_len: buf.len() };
+    let mut msg: libc::msghdr = unsafe { zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    control.attach(&mut msg);
+    loop {
+        let ret = unsafe { libc::recvmsg(fd, &mut msg, RECV_FLAGS) };
+        if ret != -1 {
+            let files = unsafe { received_fds(&msg)? };
+            return Ok((ret as usize, files));
+        }
+        let e = Error::last_os_error();
+        if e.kind() != ErrorKind::Interrupted { return Err(e); }
+    }
+}
+
+impl AsRawFd for UnixListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl UnixListener {
+    pub async fn accept(&self) -> Result<UnixStream, Error> {
+        let fd = self.0.as_raw_fd();
+        let (stream, _) = unblock(move || borrow::<net::UnixListener>(fd).accept()).await?;
+        Ok(UnixStream(stream))
+    }
+}
+
+impl AsRawFd for UnixStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl UnixStream {
+    pub fn shutdown(&self, how: Shutdown) -> Result<(), Error> {
+        self.0.shutdown(how)
+    }
+
+    pub async fn send<B: OwnedBuf>(&self, buf: B) -> (B, Result<usize, Error>) {
+        let fd = self.0.as_raw_fd();
+        unblock(move || {
+            let wrote = (&*borrow::<net::UnixStream>(fd)).write(buf.as_ref());
+            (buf, wrote)
+        }).await
+    }
+
+    pub async fn recv<B: OwnedBuf>(&self, buf: B) -> (B, Result<usize, Error>) {
+        let fd = self.0.as_raw_fd();
+        unblock(move || {
+            let mut buf = buf;
+            let read = (&*borrow::<net::UnixStream>(fd)).read(buf.as_mut());
+            (buf, read)
+        }).await
+    }
+
+    pub async fn send_fds<B: OwnedBuf>(&self, buf: B, fds: Vec<RawFd>) -> (B, Result<usize, Error>) {
+        let fd = self.0.as_raw_fd();
+        unblock(move || {
+            let wrote = send_fds(fd, buf.as_ref(), &fds);
+            (buf, wrote)
+        }).await
+    }
+
+    pub async fn recv_fds<B: OwnedBuf>(&self, buf: B, max: usize) -> (B, Result<(usize, Vec<fs::File>), Error>) {
+        let fd = self.0.as_raw_fd();
+        unblock(move || {
+            let mut buf = buf;
+            let read = recv_fds(fd, buf.as_mut(), max);
+            (buf, read)
+        }).await
+    }
+}
+
+impl AsRawFd for UnixDatagram {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl UnixDatagram {
+    pub fn connect(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        self.0.connect(path)
+    }
+
+    pub async fn send<B: OwnedBuf>(&self, buf: B) -> (B, Result<usize, Error>) {
+        let fd = self.0.as_raw_fd();
+        unblock(move || {
+            let wrote = borrow::<net::UnixDatagram>(fd).send(buf.as_ref());
+            (buf, wrote)
+        }).await
+    }
+
+    pub async fn recv<B: OwnedBuf>(&self, buf: B) -> (B, Result<usize, Error>) {
+        let fd = self.0.as_raw_fd();
+        unblock(move || {
+            let mut buf = buf;
+            let read = borrow::<net::UnixDatagram>(fd).recv(buf.as_mut());
+            (buf, read)
+        }).await
+    }
+
+    pub async fn send_fds<B: OwnedBuf>(&self, buf: B, fds: Vec<RawFd>) -> (B, Result<usize, Error>) {
+        let fd = self.0.as_raw_fd();
+        unblock(move || {
+            let wrote = send_fds(fd, buf.as_ref(), &fds);
+            (buf, wrote)
+        }).await
+    }
+
+    pub async fn recv_fds<B: OwnedBuf>(&self, buf: B, max: usize) -> (B, Result<(usize, Vec<fs::File>), Error>) {
+        let fd = self.0.as_raw_fd();
+        unblock(move || {
+            let mut buf = buf;
+            let read = recv_fds(fd, buf.as_mut(), max);
+            (buf, read)
+        }).await
+    }
+}
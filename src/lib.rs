@@ -1,15 +1,43 @@
 #![feature(io_slice_advance)]
-// mod buffer;
-// pub use buffer::Buffer;
 
 #[cfg(feature = "ringbahn")]
-mod ringbahn;
+pub mod ringbahn;
 
+mod arena;
 mod buffer;
+mod copy;
+mod cursor;
+mod direct;
+mod fs;
+mod io;
 mod mmap;
+mod net;
+mod numa;
+mod paged;
+mod pool;
+mod sites;
+#[cfg(target_os = "linux")]
+mod splice;
+mod unix;
 
 pub mod legacy;
 
+pub use buffer::{Buffer, HugePages, PageConfig};
+pub use copy::CopyStrategy;
+pub use cursor::{BufferReader, BufferWriter, Chunks};
+pub use direct::DirectAlign;
+#[cfg(target_os = "linux")]
+pub use direct::DirectIo;
+#[cfg(target_os = "linux")]
+pub use fs::{RenameFlags, Statx, StatxTimestamp, SyncRangeFlags, STATX_ALL, STATX_BASIC_STATS, STATX_DIOALIGN};
+pub use io::{Backend, File, IO, OwnedBuf};
+pub use net::{Datagram, Datagrams, Incoming, RecvStream, TcpListener, TcpStream, UdpSocket};
+pub use numa::NumaNode;
+pub use paged::{ReadBuffer, WriteBuffer};
+pub use pool::{BufferPool, PoolConfig, PoolStats};
+pub use sites::BufferSite;
+pub use unix::{UnixDatagram, UnixListener, UnixStream};
+
 #[cfg(test)]
 mod tests {
     #[test]
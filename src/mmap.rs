@@ -5,15 +5,22 @@ use libc::{
     PROT_READ, PROT_WRITE,
 };
 #[cfg(target_os = "linux")]
-use libc::mremap;
+use libc::{c_ulong, madvise, mremap, syscall, MADV_HUGEPAGE, MAP_HUGETLB, SYS_mbind};
 use std::convert::{AsRef, AsMut};
 use std::io::Error;
+#[cfg(target_os = "linux")]
+use std::mem::size_of;
 use std::fs::File;
 use std::ops::{Deref, DerefMut};
 use std::os::unix::io::AsRawFd;
 use std::ptr::null_mut;
 use std::slice;
 
+#[cfg(target_os = "linux")]
+const MPOL_PREFERRED: c_int = 1;
+#[cfg(target_os = "linux")]
+const MADV_POPULATE_WRITE: c_int = 23;
+
 #[derive(Debug)]
 pub struct Mmap {
     pub(crate) ptr: *mut u8,
@@ -104,6 +111,54 @@ impl MmapMut {
         MmapMut::new(bytes, flags, file.as_raw_fd() as c_int, offset)
     }
 
+    // Backed by the hugetlbfs pool, so `bytes` must be a multiple of the
+    // huge page size and there must be enough of them reserved.
+    #[cfg(target_os = "linux")]
+    pub fn anon_hugetlb(bytes: usize, populate: bool) -> Result<MmapMut, Error> {
+        let flags = {
+            if populate { MAP_ANONYMOUS | MAP_HUGETLB | MAP_POPULATE | MAP_PRIVATE }
+            else { MAP_ANONYMOUS | MAP_HUGETLB | MAP_PRIVATE }
+        };
+        MmapMut::new(bytes, flags, -1, 0)
+    }
+
+    // Asks for transparent huge pages. Only does anything for the parts
+    // of the mapping that aren't touched yet.
+    #[cfg(target_os = "linux")]
+    pub fn advise_huge(&mut self) -> Result<(), Error> {
+        match unsafe { madvise(self.ptr.cast(), self.size as size_t, MADV_HUGEPAGE) } {
+            -1 => Err(Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    // Prefers `node` for the pages of the mapping that aren't touched yet,
+    // falling back to others when it's out of memory.
+    #[cfg(target_os = "linux")]
+    pub fn bind_node(&mut self, node: u32) -> Result<(), Error> {
+        let bits = 8 * size_of::<c_ulong>();
+        let mut mask = vec![0 as c_ulong; node as usize / bits + 1];
+        mask[node as usize / bits] |= 1 << (node as usize % bits);
+        // The kernel counts one bit fewer than it's told to.
+        let ret = unsafe {
+            syscall(SYS_mbind, self.ptr, self.size, MPOL_PREFERRED, mask.as_ptr(), mask.len() * bits + 1, 0)
+        };
+        match ret {
+            -1 => Err(Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    // Faults the whole mapping in now, like MAP_POPULATE but after the
+    // fact. Needs Linux 5.14.
+    #[cfg(target_os = "linux")]
+    pub fn populate(&mut self) -> Result<(), Error> {
+        match unsafe { madvise(self.ptr.cast(), self.size as size_t, MADV_POPULATE_WRITE) } {
+            -1 => Err(Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
     #[cfg(target_os = "linux")]
     pub fn remap_in_place(&mut self) -> Result<(), Error> {
         memory_remap_to(self.ptr.cast(), self.size, self.ptr.cast(), self.size, self.flags | MAP_FIXED)?;
@@ -209,8 +264,21 @@ fn memory_remap_to(
 
 fn memory_unmap(ptr: *mut u8, size: usize) -> Result<(), Error> {
     match unsafe { munmap(ptr.cast(), size as size_t) } {
-        -1 => Ok(()),
-        _ => Err(Error::last_os_error()),
+        -1 => Err(Error::last_os_error()),
+        _ => Ok(()),
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unmap_reports_failure() {
+        let mmap = MmapMut::anon(4096, false).unwrap();
+        // munmap refuses an address that isn't page-aligned.
+        assert!(memory_unmap(unsafe { mmap.ptr.add(1) }, 1).is_err());
+        assert!(mmap.close().is_ok());
+    }
+}
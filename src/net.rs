@@ -0,0 +1,424 @@
+use crate::buffer::{page_size, Buffer, Writeable};
+use crate::io::OwnedBuf;
+use crate::legacy;
+use futures_core::Stream;
+#[cfg(feature = "ringbahn")]
+use crate::ringbahn;
+
+use std::io::{Error, ErrorKind};
+use std::mem::{size_of, zeroed};
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, SocketAddrV4, SocketAddrV6};
+#[cfg(feature = "ringbahn")]
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+#[cfg(feature = "ringbahn")]
+use std::path::Path;
+
+pub enum TcpListener {
+    Legacy(legacy::TcpListener),
+    #[cfg(feature = "ringbahn")]
+    Ringbahn(ringbahn::TcpListener),
+}
+
+pub enum TcpStream {
+    Legacy(legacy::TcpStream),
+    #[cfg(feature = "ringbahn")]
+    Ringbahn(ringbahn::TcpStream),
+}
+
+pub enum UdpSocket {
+    Legacy(legacy::UdpSocket),
+    #[cfg(feature = "ringbahn")]
+    Ringbahn(ringbahn::UdpSocket),
+}
+
+pub enum Incoming<'a> {
+    Legacy(legacy::Incoming<'a>),
+    #[cfg(feature = "ringbahn")]
+    Ringbahn(ringbahn::Incoming<'a>),
+}
+
+pub enum RecvStream<'a> {
+    Legacy(legacy::RecvStream<'a>),
+    #[cfg(feature = "ringbahn")]
+    Ringbahn(ringbahn::RecvStream<'a>),
+}
+
+// A socket address in the form the kernel takes it.
+#[cfg_attr(not(any(target_os = "linux", feature = "ringbahn")), allow(dead_code))]
+pub(crate) struct SockAddr {
+    pub(crate) storage: libc::sockaddr_storage,
+    pub(crate) len: libc::socklen_t,
+}
+
+#[cfg_attr(not(any(target_os = "linux", feature = "ringbahn")), allow(dead_code))]
+impl SockAddr {
+    // Room for the kernel to fill in any address.
+    pub(crate) fn empty() -> SockAddr {
+        SockAddr { storage: unsafe { zeroed() }, len: size_of::<libc::sockaddr_storage>() as libc::socklen_t }
+    }
+
+    pub(crate) fn from_addr(addr: &SocketAddr) -> SockAddr {
+        let mut storage: libc::sockaddr_storage = unsafe { zeroed() };
+        let len = match addr {
+            SocketAddr::V4(addr) => {
+                let sin = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage).cast::<libc::sockaddr_in>() };
+                sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                sin.sin_port = addr.port().to_be();
+                sin.sin_addr = libc::in_addr { s_addr: u32::from_ne_bytes(addr.ip().octets()) };
+                size_of::<libc::sockaddr_in>()
+            }
+            SocketAddr::V6(addr) => {
+                let sin6 = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage).cast::<libc::sockaddr_in6>() };
+                sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sin6.sin6_port = addr.port().to_be();
+                sin6.sin6_flowinfo = addr.flowinfo();
+                sin6.sin6_addr = libc::in6_addr { s6_addr: addr.ip().octets() };
+                sin6.sin6_scope_id = addr.scope_id();
+                size_of::<libc::sockaddr_in6>()
+            }
+        };
+        SockAddr { storage, len: len as libc::socklen_t }
+    }
+
+    #[cfg(feature = "ringbahn")]
+    pub(crate) fn from_path(path: &Path) -> Result<SockAddr, Error> {
+        let mut addr = SockAddr::empty();
+        let sun = unsafe { &mut *(&mut addr.storage as *mut libc::sockaddr_storage).cast::<libc::sockaddr_un>() };
+        let bytes = path.as_os_str().as_bytes();
+        // Room for the nul.
+        if bytes.len() >= sun.sun_path.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "path is too long for a unix socket"));
+        }
+        sun.sun_family = libc::AF_UNIX as libc::sa_family_t;
+        for (dst, src) in sun.sun_path.iter_mut().zip(bytes) {
+            *dst = *src as libc::c_char;
+        }
+        let offset = sun.sun_path.as_ptr() as usize - sun as *const libc::sockaddr_un as usize;
+        addr.len = (offset + bytes.len() + 1) as libc::socklen_t;
+        Ok(addr)
+    }
+
+    pub(crate) fn to_addr(&self) -> Result<SocketAddr, Error> {
+        match self.storage.ss_family as libc::c_int {
+            libc::AF_INET => {
+                let sin = unsafe { &*(&self.storage as *const libc::sockaddr_storage).cast::<libc::sockaddr_in>() };
+                let ip = Ipv4Addr::from(sin.sin_addr.s_addr.to_ne_bytes());
+                Ok(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(sin.sin_port))))
+            }
+            libc::AF_INET6 => {
+                let sin6 = unsafe { &*(&self.storage as *const libc::sockaddr_storage).cast::<libc::sockaddr_in6>() };
+                let ip = Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+                Ok(SocketAddr::V6(SocketAddrV6::new(
+                    ip, u16::from_be(sin6.sin6_port), sin6.sin6_flowinfo, sin6.sin6_scope_id
+                )))
+            }
+            _ => Err(Error::new(ErrorKind::InvalidData, "unexpected address family")),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Datagram {
+    pub(crate) len: usize,
+    pub(crate) addr: SocketAddr,
+    pub(crate) truncated: bool,
+}
+
+impl Datagram {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Who it came from or is going to.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    // Whether the datagram was longer than a page and lost its tail.
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+}
+
+// A batch of datagrams for UdpSocket::send_batch and recv_batch, one per
+// page of a Buffer. Anything longer than a page is refused by push and
+// truncated on receipt.
+pub struct Datagrams {
+    pub(crate) buffer: Buffer,
+    pub(crate) datagrams: Vec<Datagram>,
+}
+
+impl Datagrams {
+    pub fn new() -> Datagrams {
+        Datagrams { buffer: Buffer::new(), datagrams: Vec::new() }
+    }
+
+    pub fn with_capacity(count: usize) -> Result<Datagrams, Error> {
+        Ok(Datagrams { buffer: Buffer::with_pages(count)?, datagrams: Vec::with_capacity(count) })
+    }
+
+    pub fn len(&self) -> usize {
+        self.datagrams.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.datagrams.is_empty()
+    }
+
+    // Forgets the datagrams but keeps the pages.
+    pub fn clear(&mut self) {
+        self.datagrams.clear();
+    }
+
+    pub fn push(&mut self, data: &[u8], addr: SocketAddr) -> Result<(), Error> {
+        if data.len() > page_size() {
+            return Err(Error::new(ErrorKind::InvalidInput, "datagram is larger than a page"));
+        }
+        let page = Writeable::new(&mut self.buffer, self.datagrams.len() * page_size()).next_slice()?;
+        page[..data.len()].copy_from_slice(data);
+        self.datagrams.push(Datagram { len: data.len(), addr, truncated: false });
+        Ok(())
+    }
+
+    pub fn get(&self, index: usize) -> Option<(&[u8], Datagram)> {
+        let datagram = *self.datagrams.get(index)?;
+        Some((self.buffer.read_block(index, datagram.len)?, datagram))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], Datagram)> {
+        (0..self.len()).filter_map(move |index| self.get(index))
+    }
+}
+
+impl Default for Datagrams {
+    fn default() -> Datagrams {
+        Datagrams::new()
+    }
+}
+
+impl AsRawFd for TcpListener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            TcpListener::Legacy(listener) => listener.as_raw_fd(),
+            #[cfg(feature = "ringbahn")]
+            TcpListener::Ringbahn(listener) => listener.as_raw_fd(),
+        }
+    }
+}
+
+impl TcpListener {
+    pub async fn accept(&self) -> Result<(TcpStream, SocketAddr), Error> {
+        match self {
+            TcpListener::Legacy(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((TcpStream::Legacy(stream), addr))
+            }
+            #[cfg(feature = "ringbahn")]
+            TcpListener::Ringbahn(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((TcpStream::Ringbahn(stream), addr))
+            }
+        }
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        match self {
+            TcpListener::Legacy(listener) => listener.local_addr(),
+            #[cfg(feature = "ringbahn")]
+            TcpListener::Ringbahn(listener) => listener.local_addr(),
+        }
+    }
+
+    // Every connection as it's accepted. Never ends. On io_uring this is
+    // a multishot ACCEPT where the kernel has it (5.19).
+    pub fn incoming(&self) -> Incoming<'_> {
+        match self {
+            TcpListener::Legacy(listener) => Incoming::Legacy(listener.incoming()),
+            #[cfg(feature = "ringbahn")]
+            TcpListener::Ringbahn(listener) => Incoming::Ringbahn(listener.incoming()),
+        }
+    }
+}
+
+impl<'a> Stream for Incoming<'a> {
+    type Item = Result<(TcpStream, SocketAddr), Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.get_mut() {
+            Incoming::Legacy(incoming) => Pin::new(incoming).poll_next(cx)
+                .map(|next| next.map(|accepted| accepted.map(|(stream, addr)| (TcpStream::Legacy(stream), addr)))),
+            #[cfg(feature = "ringbahn")]
+            Incoming::Ringbahn(incoming) => Pin::new(incoming).poll_next(cx)
+                .map(|next| next.map(|accepted| accepted.map(|(stream, addr)| (TcpStream::Ringbahn(stream), addr)))),
+        }
+    }
+}
+
+impl AsRawFd for TcpStream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            TcpStream::Legacy(stream) => stream.as_raw_fd(),
+            #[cfg(feature = "ringbahn")]
+            TcpStream::Ringbahn(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+impl TcpStream {
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        match self {
+            TcpStream::Legacy(stream) => stream.local_addr(),
+            #[cfg(feature = "ringbahn")]
+            TcpStream::Ringbahn(stream) => stream.local_addr(),
+        }
+    }
+
+    pub fn peer_addr(&self) -> Result<SocketAddr, Error> {
+        match self {
+            TcpStream::Legacy(stream) => stream.peer_addr(),
+            #[cfg(feature = "ringbahn")]
+            TcpStream::Ringbahn(stream) => stream.peer_addr(),
+        }
+    }
+
+    pub fn set_nodelay(&self, nodelay: bool) -> Result<(), Error> {
+        match self {
+            TcpStream::Legacy(stream) => stream.set_nodelay(nodelay),
+            #[cfg(feature = "ringbahn")]
+            TcpStream::Ringbahn(stream) => stream.set_nodelay(nodelay),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> Result<(), Error> {
+        match self {
+            TcpStream::Legacy(stream) => stream.shutdown(how),
+            #[cfg(feature = "ringbahn")]
+            TcpStream::Ringbahn(stream) => stream.shutdown(how),
+        }
+    }
+
+    // Receives into `buf`, handing it back with the count. 0 means the
+    // peer has shut down its side.
+    pub async fn recv<B: OwnedBuf>(&self, buf: B) -> (B, Result<usize, Error>) {
+        match self {
+            TcpStream::Legacy(stream) => stream.recv(buf).await,
+            #[cfg(feature = "ringbahn")]
+            TcpStream::Ringbahn(stream) => stream.recv(buf).await,
+        }
+    }
+
+    // Sends as much of `buf` as the socket will take, handing it back
+    // with the count.
+    pub async fn send<B: OwnedBuf>(&self, buf: B) -> (B, Result<usize, Error>) {
+        match self {
+            TcpStream::Legacy(stream) => stream.send(buf).await,
+            #[cfg(feature = "ringbahn")]
+            TcpStream::Ringbahn(stream) => stream.send(buf).await,
+        }
+    }
+
+    // Whatever arrives, a page at a time from the buffer pool, until the
+    // peer shuts down its side. On io_uring this is a multishot RECV into
+    // a ring of pool pages where the kernel has it (6.0).
+    pub fn recv_stream(&self) -> RecvStream<'_> {
+        match self {
+            TcpStream::Legacy(stream) => RecvStream::Legacy(stream.recv_stream()),
+            #[cfg(feature = "ringbahn")]
+            TcpStream::Ringbahn(stream) => RecvStream::Ringbahn(stream.recv_stream()),
+        }
+    }
+
+    pub(crate) async fn recv_pages(&self, buf: Buffer, high: usize) -> (Buffer, Result<usize, Error>) {
+        match self {
+            TcpStream::Legacy(stream) => stream.recv_pages(buf, high).await,
+            #[cfg(feature = "ringbahn")]
+            TcpStream::Ringbahn(stream) => stream.recv_pages(buf, high).await,
+        }
+    }
+
+    pub(crate) async fn send_pages(&self, buf: Buffer, low: usize, high: usize) -> (Buffer, Result<usize, Error>) {
+        match self {
+            TcpStream::Legacy(stream) => stream.send_pages(buf, low, high).await,
+            #[cfg(feature = "ringbahn")]
+            TcpStream::Ringbahn(stream) => stream.send_pages(buf, low, high).await,
+        }
+    }
+}
+
+impl<'a> Stream for RecvStream<'a> {
+    // A page and how much of it was filled.
+    type Item = Result<(Buffer, usize), Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.get_mut() {
+            RecvStream::Legacy(recv) => Pin::new(recv).poll_next(cx),
+            #[cfg(feature = "ringbahn")]
+            RecvStream::Ringbahn(recv) => Pin::new(recv).poll_next(cx),
+        }
+    }
+}
+
+impl AsRawFd for UdpSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            UdpSocket::Legacy(socket) => socket.as_raw_fd(),
+            #[cfg(feature = "ringbahn")]
+            UdpSocket::Ringbahn(socket) => socket.as_raw_fd(),
+        }
+    }
+}
+
+impl UdpSocket {
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        match self {
+            UdpSocket::Legacy(socket) => socket.local_addr(),
+            #[cfg(feature = "ringbahn")]
+            UdpSocket::Ringbahn(socket) => socket.local_addr(),
+        }
+    }
+
+    pub async fn send_to<B: OwnedBuf>(&self, buf: B, addr: SocketAddr) -> (B, Result<usize, Error>) {
+        match self {
+            UdpSocket::Legacy(socket) => socket.send_to(buf, addr).await,
+            #[cfg(feature = "ringbahn")]
+            UdpSocket::Ringbahn(socket) => socket.send_to(buf, addr).await,
+        }
+    }
+
+    // Receives one datagram into `buf`; anything that doesn't fit is lost.
+    pub async fn recv_from<B: OwnedBuf>(&self, buf: B) -> (B, Result<(usize, SocketAddr), Error>) {
+        match self {
+            UdpSocket::Legacy(socket) => socket.recv_from(buf).await,
+            #[cfg(feature = "ringbahn")]
+            UdpSocket::Ringbahn(socket) => socket.recv_from(buf).await,
+        }
+    }
+
+    // Sends every datagram in the batch, returning how many went out. An
+    // error is only returned if none did. With sendmmsg they go in order;
+    // on io_uring they're submitted together and may not.
+    pub async fn send_batch(&self, batch: Datagrams) -> (Datagrams, Result<usize, Error>) {
+        match self {
+            UdpSocket::Legacy(socket) => socket.send_batch(batch).await,
+            #[cfg(feature = "ringbahn")]
+            UdpSocket::Ringbahn(socket) => socket.send_batch(batch).await,
+        }
+    }
+
+    // Waits for a datagram, then takes up to `max` of whatever else has
+    // arrived without waiting again. Replaces the batch's contents.
+    pub async fn recv_batch(&self, batch: Datagrams, max: usize) -> (Datagrams, Result<usize, Error>) {
+        match self {
+            UdpSocket::Legacy(socket) => socket.recv_batch(batch, max).await,
+            #[cfg(feature = "ringbahn")]
+            UdpSocket::Ringbahn(socket) => socket.recv_batch(batch, max).await,
+        }
+    }
+}
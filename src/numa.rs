@@ -0,0 +1,138 @@
+// Which NUMA node things are on, going by sysfs. Without NUMA (or off
+// linux) there are no nodes, and everything just uses the default pool.
+
+use once_cell::sync::Lazy;
+
+use std::io::Error;
+#[cfg(target_os = "linux")]
+use std::io::ErrorKind;
+#[cfg(target_os = "linux")]
+use std::mem::MaybeUninit;
+use std::os::unix::io::AsRawFd;
+#[cfg(target_os = "linux")]
+use std::{fs, ptr};
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct NumaNode(u32);
+
+// One more than the highest node the kernel could ever bring up.
+static NODES: Lazy<usize> = Lazy::new(|| {
+    #[cfg(target_os = "linux")]
+    {
+        fs::read_to_string("/sys/devices/system/node/possible").ok()
+            .and_then(|list| last_in_list(&list))
+            .map_or(0, |last| last + 1)
+    }
+    #[cfg(not(target_os = "linux"))]
+    { 0 }
+});
+
+pub(crate) fn node_count() -> usize {
+    *NODES
+}
+
+impl NumaNode {
+    pub fn new(id: u32) -> Option<NumaNode> {
+        if (id as usize) < node_count() { Some(NumaNode(id)) } else { None }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.0
+    }
+
+    // The node of the CPU we're running on, which may change as soon as
+    // the scheduler moves us.
+    pub fn current() -> Option<NumaNode> {
+        #[cfg(target_os = "linux")]
+        {
+            let mut cpu: u32 = 0;
+            let mut node: u32 = 0;
+            let ret = unsafe {
+                libc::syscall(libc::SYS_getcpu, &mut cpu, &mut node, ptr::null_mut::<libc::c_void>())
+            };
+            if ret == -1 { None } else { NumaNode::new(node) }
+        }
+        #[cfg(not(target_os = "linux"))]
+        { None }
+    }
+
+    // The node of the block device under a file, if it has one. Files on
+    // anything that isn't a plain disk (tmpfs, network and device-mapper
+    // filesystems) don't.
+    pub fn of_file(file: &impl AsRawFd) -> Result<Option<NumaNode>, Error> {
+        #[cfg(target_os = "linux")]
+        {
+            let mut stat = MaybeUninit::<libc::stat>::uninit();
+            if unsafe { libc::fstat(file.as_raw_fd(), stat.as_mut_ptr()) } == -1 {
+                return Err(Error::last_os_error());
+            }
+            let stat = unsafe { stat.assume_init() };
+            let dev = if stat.st_mode & libc::S_IFMT == libc::S_IFBLK { stat.st_rdev } else { stat.st_dev };
+            let dir = format!("/sys/dev/block/{}:{}", libc::major(dev), libc::minor(dev));
+            // A partition has no device of its own, and an NVMe namespace's
+            // device is the controller, whose device is the PCI function.
+            for path in &["device", "device/device", "../device", "../device/device"] {
+                if let Some(node) = read_node(&format!("{}/{}/numa_node", dir, path))? {
+                    return Ok(Some(node));
+                }
+            }
+            Ok(None)
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = file.as_raw_fd();
+            Ok(None)
+        }
+    }
+
+    // The node of a network interface, such as "eth0".
+    pub fn of_net_device(name: &str) -> Result<Option<NumaNode>, Error> {
+        #[cfg(target_os = "linux")]
+        {
+            if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+                return Err(Error::new(ErrorKind::InvalidInput, "not a network interface name"));
+            }
+            read_node(&format!("/sys/class/net/{}/device/numa_node", name))
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = name;
+            Ok(None)
+        }
+    }
+}
+
+// None if there's no such file, or it says -1 for no particular node.
+// Some devices' `device` is an attribute rather than a link, hence
+// ENOTDIR.
+#[cfg(target_os = "linux")]
+fn read_node(path: &str) -> Result<Option<NumaNode>, Error> {
+    match fs::read_to_string(path) {
+        Ok(node) => Ok(node.trim().parse().ok().and_then(NumaNode::new)),
+        Err(e) if e.kind() == ErrorKind::NotFound || e.raw_os_error() == Some(libc::ENOTDIR) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// The last number in a list like "0-3,8,10-11".
+#[cfg(target_os = "linux")]
+fn last_in_list(list: &str) -> Option<usize> {
+    list.trim().split(',')
+        .filter_map(|range| range.rsplit('-').next()?.parse().ok())
+        .max()
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::last_in_list;
+
+    #[test]
+    fn lists() {
+        assert_eq!(last_in_list("0\n"), Some(0));
+        assert_eq!(last_in_list("0-3\n"), Some(3));
+        assert_eq!(last_in_list("0-3,8,10-11\n"), Some(11));
+        // Highest, not last.
+        assert_eq!(last_in_list("4,0-2"), Some(4));
+        assert_eq!(last_in_list("\n"), None);
+    }
+}
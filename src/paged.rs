@@ -0,0 +1,486 @@
+#[cfg(feature = "bytes")]
+use bytes::{buf::UninitSlice, Buf, BufMut};
+#[cfg(feature = "bytes")]
+use crate::cursor::uninit;
+use crate::buffer::{Buffer, Writeable};
+use crate::cursor::Chunks;
+use crate::io::File;
+use crate::net::TcpStream;
+
+use std::borrow::Cow;
+use std::cmp::min;
+use std::io::{self, BufRead, Error, ErrorKind, IoSlice, Read, Write};
+use std::mem::replace;
+
+pub struct ReadBuffer {
+    pub(crate) buffer: Buffer,
+    pub(crate) high: usize,
+    pub(crate) low: usize,
+}
+
+impl ReadBuffer {
+    pub fn new() -> ReadBuffer {
+        ReadBuffer::from_buffer(Buffer::new())
+    }
+
+    pub fn with_capacity(bytes: usize) -> Result<ReadBuffer, Error> {
+        Ok(ReadBuffer::from_buffer(Buffer::with_capacity(bytes)?))
+    }
+
+    pub fn from_buffer(buffer: Buffer) -> ReadBuffer {
+        ReadBuffer { buffer, high: 0, low: 0 }
+    }
+
+    pub fn clear(&mut self) {
+        self.high = 0;
+        self.low = 0;
+    }
+
+    pub fn consume(&mut self, bytes: usize) {
+        self.low = min(self.low + bytes, self.high);
+        if self.low == self.high { self.clear(); }
+    }
+
+    pub fn len(&self) -> usize {
+        self.high - self.low
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.high == self.low
+    }
+
+    // What's been filled and not consumed, page by page.
+    pub fn chunks(&self) -> Chunks<'_> {
+        self.buffer.chunks(self.low..self.high)
+    }
+
+    pub fn as_io_slices(&self) -> Vec<IoSlice<'_>> {
+        self.chunks().map(IoSlice::new).collect()
+    }
+
+    // Copies as much as fits without consuming any of it.
+    pub fn copy_to(&self, buf: &mut [u8]) -> usize {
+        let mut copied = 0;
+        for chunk in self.chunks() {
+            if copied == buf.len() { break; }
+            let len = min(chunk.len(), buf.len() - copied);
+            buf[copied..copied + len].copy_from_slice(&chunk[..len]);
+            copied += len;
+        }
+        copied
+    }
+
+    // The next `bytes` bytes in one slice, if that many are filled. It's
+    // only copied if it spans pages.
+    pub fn peek(&self, bytes: usize) -> Option<Cow<'_, [u8]>> {
+        if bytes > self.len() { return None; }
+        match self.buffer.read_first(self.low, bytes) {
+            Some(first) if first.len() == bytes => Some(Cow::Borrowed(first)),
+            _ => {
+                let mut copy = vec![0; bytes];
+                self.copy_to(&mut copy);
+                Some(Cow::Owned(copy))
+            }
+        }
+    }
+
+    pub async fn fill_at(&mut self, file: &File, offset: usize) -> Result<usize, Error> {
+        if let Some(align) = file.direct_align() {
+            align.check(self.high, offset, self.buffer.capacity() - self.high)?;
+        }
+        let buf = replace(&mut self.buffer, Buffer::new());
+        let (buf2, read) = file.read_pages(buf, self.high, offset).await;
+        #[allow(unused_must_use)]
+        { replace(&mut self.buffer, buf2); }
+        let read = read?;
+        self.high += read;
+        Ok(read)
+    }
+
+    // Fills `len` bytes from `offset` in the file, as many reads as it
+    // takes. Fewer means the file ended first.
+    pub async fn read_exact_at(&mut self, file: &File, offset: usize, len: usize) -> Result<usize, Error> {
+        let start = self.high;
+        self.buffer.reserve(start + len)?;
+        while self.high - start < len {
+            match self.fill_at(file, offset + self.high - start).await {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => { return Err(e); }
+            }
+        }
+        // The last read can run past the range into spare capacity.
+        self.high = min(self.high, start + len);
+        Ok(self.high - start)
+    }
+
+    // Receives whatever the socket has into the spare capacity. 0 means
+    // the peer has shut down its side (or there was no room).
+    pub async fn fill_from(&mut self, stream: &TcpStream) -> Result<usize, Error> {
+        let buf = replace(&mut self.buffer, Buffer::new());
+        let (buf2, read) = stream.recv_pages(buf, self.high).await;
+        #[allow(unused_must_use)]
+        { replace(&mut self.buffer, buf2); }
+        let read = read?;
+        self.high += read;
+        Ok(read)
+    }
+}
+
+pub struct WriteBuffer {
+    pub(crate) buffer: Buffer,
+    pub(crate) high: usize,
+    pub(crate) low: usize,
+}
+
+impl WriteBuffer {
+    pub fn new() -> WriteBuffer {
+        WriteBuffer::from_buffer(Buffer::new())
+    }
+
+    pub fn with_capacity(bytes: usize) -> Result<WriteBuffer, Error> {
+        Ok(WriteBuffer::from_buffer(Buffer::with_capacity(bytes)?))
+    }
+
+    pub fn from_buffer(buffer: Buffer) -> WriteBuffer {
+        WriteBuffer { buffer, high: 0, low: 0 }
+    }
+
+    pub fn clear(&mut self) {
+        self.high = 0;
+        self.low = 0;
+    }
+
+    pub fn buffer(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        if buf.is_empty() { return Ok(0) }
+        let mut buf = buf;
+        let mut wrote: usize = 0;
+        let mut wr = Writeable::new(&mut self.buffer, self.high);
+        let mut w = Some(wr.next_slice()?);
+        loop {
+            let bl = buf.len();
+            let w2 = w.take().unwrap();
+            let wl = w2.len();
+            if bl > wl {
+                {
+                    let w3 = w2;
+                    w3.copy_from_slice(&buf[..wl]);
+                }
+                buf = &buf[wl..];
+                wrote += wl;
+                match wr.next_slice() {
+                    Ok(slice) => { w = Some(slice); }
+                    Err(e) => {
+                        self.high += wrote;
+                        return Err(e);
+                    }
+                }
+            } else {
+                if wl > bl {
+                    (&mut w2[..bl]).copy_from_slice(buf);
+                } else {
+                    w2.copy_from_slice(buf);
+                }
+                wrote += bl;
+                break;
+            }
+        }
+        self.high += wrote;
+        Ok(wrote)
+    }
+
+    pub fn len(&self) -> usize {
+        self.high - self.low
+    }
+
+    pub async fn write_at(&mut self, file: &File, offset: usize, sync: bool) -> Result<(), Error> {
+        if let Some(align) = file.direct_align() {
+            align.check(self.low, offset, self.len())?;
+        }
+        let buf = replace(&mut self.buffer, Buffer::new());
+        let (buf2, count) = file.write_pages(buf, self.low, self.high, offset, sync).await;
+        #[allow(unused_must_use)]
+        { replace(&mut self.buffer, buf2); }
+        self.low += count?;
+        if self.low == self.high {
+            self.clear();
+        }
+        Ok(())
+    }
+
+    pub async fn write_all_at(&mut self, file: &File, offset: usize, sync: bool) -> Result<(), Error> {
+        if let Some(align) = file.direct_align() {
+            align.check(self.low, offset, self.len())?;
+        }
+        let buf = replace(&mut self.buffer, Buffer::new());
+        let (buf2, res) = file.write_all_pages(buf, self.low, self.high, offset, sync).await;
+        #[allow(unused_must_use)]
+        { replace(&mut self.buffer, buf2); }
+        res?;
+        self.clear();
+        Ok(())
+    }
+
+    pub async fn send_to(&mut self, stream: &TcpStream) -> Result<(), Error> {
+        let buf = replace(&mut self.buffer, Buffer::new());
+        let (buf2, count) = stream.send_pages(buf, self.low, self.high).await;
+        #[allow(unused_must_use)]
+        { replace(&mut self.buffer, buf2); }
+        self.low += count?;
+        if self.low == self.high {
+            self.clear();
+        }
+        Ok(())
+    }
+
+    pub async fn send_all_to(&mut self, stream: &TcpStream) -> Result<(), Error> {
+        while self.len() > 0 {
+            match self.send_to(stream).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => { return Err(e); }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Reading takes from what's been filled, as consume does.
+impl Read for ReadBuffer {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.buffer.reader(self.low..self.high).read(buf)?;
+        self.consume(read);
+        Ok(read)
+    }
+}
+
+impl BufRead for ReadBuffer {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        Ok(self.buffer.read_first(self.low, self.high - self.low).unwrap_or(&[]))
+    }
+
+    fn consume(&mut self, bytes: usize) {
+        ReadBuffer::consume(self, bytes)
+    }
+}
+
+#[cfg(feature = "bytes")]
+impl Buf for ReadBuffer {
+    fn remaining(&self) -> usize {
+        self.high - self.low
+    }
+
+    fn chunk(&self) -> &[u8] {
+        self.buffer.read_first(self.low, self.high - self.low).unwrap_or(&[])
+    }
+
+    fn advance(&mut self, bytes: usize) {
+        assert!(bytes <= self.high - self.low, "advanced {} bytes past the end", bytes - (self.high - self.low));
+        self.consume(bytes);
+    }
+}
+
+// Like buffer, but a write that runs out of pages partway reports what
+// it managed rather than an error.
+impl Write for WriteBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let wrote = self.buffer.writer(self.high).write(buf)?;
+        self.high += wrote;
+        Ok(wrote)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// BufMut can't fail, so running out of pages panics, as it would for a
+// Vec running out of memory.
+#[cfg(feature = "bytes")]
+unsafe impl BufMut for WriteBuffer {
+    fn remaining_mut(&self) -> usize {
+        usize::MAX - self.high
+    }
+
+    fn chunk_mut(&mut self) -> &mut UninitSlice {
+        let spare = Writeable::new(&mut self.buffer, self.high).next_slice();
+        uninit(spare.expect("couldn't add a page to the buffer"))
+    }
+
+    unsafe fn advance_mut(&mut self, bytes: usize) {
+        self.high += bytes;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_lite::future::block_on;
+    use super::{ReadBuffer, WriteBuffer};
+    use crate::buffer::Buffer;
+    use crate::io::IO;
+    use crate::pool::{BufferPool, PoolConfig};
+
+    use std::borrow::Cow;
+    use std::fs::{self, OpenOptions};
+    use std::io::{BufRead, ErrorKind, Read, Write};
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn filled(data: &[u8]) -> ReadBuffer {
+        let mut buffer = Buffer::new();
+        buffer.writer(0).write_all(data).unwrap();
+        ReadBuffer { buffer, high: data.len(), low: 0 }
+    }
+
+    #[test]
+    fn peek() {
+        let page = Buffer::page_size();
+        let data = data(page + 10);
+        let mut read = filled(&data);
+        assert!(matches!(read.peek(page), Some(Cow::Borrowed(peeked)) if peeked == &data[..page]));
+        read.consume(page - 5);
+        assert!(matches!(read.peek(10), Some(Cow::Owned(peeked)) if peeked == data[page - 5..page + 5]));
+        assert!(matches!(read.peek(5), Some(Cow::Borrowed(_))));
+        assert_eq!(read.peek(16), None);
+        assert_eq!(read.peek(15).unwrap().len(), 15);
+    }
+
+    #[test]
+    fn copy_to() {
+        let page = Buffer::page_size();
+        let data = data(page + 10);
+        let mut read = filled(&data);
+        read.consume(page - 5);
+        let mut out = [0; 10];
+        assert_eq!(read.copy_to(&mut out), 10);
+        assert_eq!(out, data[page - 5..page + 5]);
+        let mut out = [0; 20];
+        assert_eq!(read.copy_to(&mut out), 15);
+        assert_eq!(out[..15], data[page - 5..]);
+        // Nothing's consumed.
+        assert_eq!(read.len(), 15);
+        assert_eq!(ReadBuffer::new().copy_to(&mut out), 0);
+    }
+
+    #[test]
+    fn io_slices() {
+        let page = Buffer::page_size();
+        let data = data(2 * page + 10);
+        let mut read = filled(&data);
+        read.consume(5);
+        let slices = read.as_io_slices();
+        assert_eq!(slices.iter().map(|slice| slice.len()).collect::<Vec<_>>(), [page - 5, page, 10]);
+        assert_eq!(slices.iter().map(|slice| &slice[..]).collect::<Vec<_>>().concat(), data[5..]);
+        assert!(ReadBuffer::new().as_io_slices().is_empty());
+    }
+
+    #[test]
+    fn read_exact_at() {
+        let page = Buffer::page_size();
+        let data = data(3 * page);
+        let path = std::env::temp_dir().join(format!("io-backplane-paged-{}", std::process::id()));
+        fs::write(&path, &data).unwrap();
+        block_on(async {
+            let file = IO::legacy().open_file(&path, OpenOptions::new().read(true)).await.unwrap();
+            let mut read = ReadBuffer::new();
+            assert!(read.is_empty());
+            assert_eq!(read.read_exact_at(&file, 100, page).await.unwrap(), page);
+            // Appends after what's already there.
+            assert_eq!(read.read_exact_at(&file, 100 + page, 50).await.unwrap(), 50);
+            assert_eq!(read.len(), page + 50);
+            assert_eq!(read.chunks().collect::<Vec<_>>().concat(), data[100..150 + page]);
+            // Short at the end of the file.
+            read.clear();
+            assert_eq!(read.read_exact_at(&file, 2 * page + 10, page).await.unwrap(), page - 10);
+            assert_eq!(read.chunks().collect::<Vec<_>>().concat(), data[2 * page + 10..]);
+            assert_eq!(read.read_exact_at(&file, 3 * page, 10).await.unwrap(), 0);
+        });
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn read_consumes() {
+        let page = Buffer::page_size();
+        let data = data(page + 10);
+        let mut read = filled(&data);
+        read.consume(page - 2);
+        let mut out = [0; 4];
+        assert_eq!(read.read(&mut out).unwrap(), 4);
+        assert_eq!(out, data[page - 2..page + 2]);
+        assert_eq!(read.len(), 8);
+        assert!(!read.is_empty());
+        let mut rest = Vec::new();
+        assert_eq!(read.read_to_end(&mut rest).unwrap(), 8);
+        assert_eq!(rest, data[page + 2..]);
+        // All consumed, so it starts over from the front.
+        assert_eq!((read.low, read.high), (0, 0));
+        assert!(read.is_empty());
+        assert_eq!(read.read(&mut out).unwrap(), 0);
+    }
+
+    #[test]
+    fn buf_read() {
+        let page = Buffer::page_size();
+        let data = data(page + 10);
+        let mut read = filled(&data);
+        BufRead::consume(&mut read, page - 2);
+        assert_eq!(read.fill_buf().unwrap(), &data[page - 2..page]);
+        BufRead::consume(&mut read, 2);
+        assert_eq!(read.fill_buf().unwrap(), &data[page..]);
+        BufRead::consume(&mut read, 100);
+        assert_eq!(read.fill_buf().unwrap(), b"");
+        assert_eq!(ReadBuffer::new().fill_buf().unwrap(), b"");
+    }
+
+    #[test]
+    fn write_appends() {
+        let page = Buffer::page_size();
+        let data = data(page + 10);
+        let mut write = WriteBuffer::new();
+        write.write_all(&data[..5]).unwrap();
+        write.write_all(&data[5..]).unwrap();
+        assert_eq!(write.len(), data.len());
+        assert_eq!(write.buffer.chunks(0..write.high).collect::<Vec<_>>().concat(), data);
+    }
+
+    #[test]
+    fn write_short_at_pool_limit() {
+        let page = Buffer::page_size();
+        let pool = BufferPool::new(PoolConfig { max_pages: 1, ..PoolConfig::default() }).unwrap();
+        let mut write = WriteBuffer::from_buffer(pool.try_acquire(1).unwrap());
+        assert_eq!(write.write(&data(page + 1)).unwrap(), page);
+        assert_eq!(write.len(), page);
+        assert_eq!(write.write(&[0]).unwrap_err().kind(), ErrorKind::WouldBlock);
+        assert_eq!(write.len(), page);
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn bytes() {
+        use bytes::{Buf, BufMut};
+
+        let page = Buffer::page_size();
+        let mut write = WriteBuffer::new();
+        write.put_slice(&data(page - 2));
+        write.put_u32(0x01020304);
+        assert_eq!(write.len(), page + 2);
+        assert_eq!(write.buffer.capacity(), 2 * page);
+
+        let mut read = ReadBuffer { buffer: write.buffer, high: write.high, low: 0 };
+        read.advance(page - 2);
+        assert_eq!(Buf::chunk(&read).len(), 2);
+        assert_eq!(read.get_u32(), 0x01020304);
+        assert_eq!(read.remaining(), 0);
+        assert_eq!((read.low, read.high), (0, 0));
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    #[should_panic(expected = "advanced 1 bytes past the end")]
+    fn advance_past_end() {
+        bytes::Buf::advance(&mut filled(&[0; 4]), 5);
+    }
+}
@@ -0,0 +1,437 @@
+// Where Buffers get their pages. Buffer::new and friends use a default
+// pool with no limits; a BufferPool of your own can cap how many pages it
+// hands out and give idle memory back to the OS. With NUMA there's also a
+// pool per node, whose arenas prefer that node's memory.
+
+use concurrent_queue::ConcurrentQueue;
+use once_cell::sync::Lazy;
+use crate::arena::{Arena, Block};
+use crate::buffer::Buffer;
+use crate::numa::{node_count, NumaNode};
+
+use std::cell::RefCell;
+use std::cmp::min;
+use std::future::Future;
+use std::io::{Error, ErrorKind};
+use std::iter;
+use std::ops::Deref;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PoolConfig {
+    // The most pages handed out at once. Arenas are no bigger than this,
+    // and a new one is only mapped when all the others are full, so what's
+    // mapped stays under twice this.
+    pub max_pages: usize,
+    // Once more than `high_watermark` pages are mapped but not in use,
+    // arenas with nothing in use are unmapped for as long as that leaves
+    // at least `low_watermark` of them.
+    pub high_watermark: usize,
+    pub low_watermark: usize,
+    // Binds the pool's arenas to this node's memory.
+    pub node: Option<NumaNode>,
+}
+
+impl Default for PoolConfig {
+    fn default() -> PoolConfig {
+        PoolConfig { max_pages: usize::MAX, high_watermark: usize::MAX, low_watermark: 0, node: None }
+    }
+}
+
+pub(crate) struct Pool {
+    config: PoolConfig,
+    // Where the process-wide pools keep their pages in threads' caches.
+    slot: Option<usize>,
+    // Arenas that had free pages when we last looked.
+    available: ConcurrentQueue<Arc<Arena>>,
+    // Every arena still mapped. Held while mapping or unmapping them.
+    arenas: Mutex<Vec<Arc<Arena>>>,
+    // Pages out of the arenas, counting those in threads' caches.
+    in_use: AtomicUsize,
+    mapped: AtomicUsize,
+    // Tasks waiting in acquire. Every release wakes them all to try again.
+    waiters: ConcurrentQueue<Waker>,
+    alloc_failures: AtomicU64,
+    mmap_calls: AtomicU64,
+    munmap_calls: AtomicU64,
+}
+
+// A snapshot of a pool, for metrics. The counts are read one at a time
+// while other threads carry on, so they needn't add up exactly.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct PoolStats {
+    // Pages in the arenas we have mapped.
+    pub mapped_pages: usize,
+    // Pages in those arenas that can be taken without going over the
+    // pool's limit.
+    pub free_pages: usize,
+    // Pages out of the arenas: held by Buffers, or in a thread's cache,
+    // which holds up to 64 of each process-wide pool's pages.
+    pub in_use_pages: usize,
+    // Times a page couldn't be had, for being at the limit or out of
+    // memory. Waiting in acquire doesn't count.
+    pub alloc_failures: u64,
+    // Arenas mapped and unmapped.
+    pub mmap_calls: u64,
+    pub munmap_calls: u64,
+}
+
+impl PoolStats {
+    fn add(self, other: PoolStats) -> PoolStats {
+        PoolStats {
+            mapped_pages: self.mapped_pages + other.mapped_pages,
+            free_pages: self.free_pages + other.free_pages,
+            in_use_pages: self.in_use_pages + other.in_use_pages,
+            alloc_failures: self.alloc_failures + other.alloc_failures,
+            mmap_calls: self.mmap_calls + other.mmap_calls,
+            munmap_calls: self.munmap_calls + other.munmap_calls,
+        }
+    }
+}
+
+// The process-wide pools: the default one, then one for each node.
+static SHARED: Lazy<Vec<Arc<Pool>>> = Lazy::new(|| {
+    (0..=node_count()).map(|slot| {
+        let node = if slot == 0 { None } else { NumaNode::new(slot as u32 - 1) };
+        Pool::new(PoolConfig { node, ..PoolConfig::default() }, Some(slot))
+    }).collect()
+});
+
+// How many pages a thread's cache takes from or gives back to the arenas
+// at once, and how many it holds before giving some back.
+const CACHE_BATCH: usize = 32;
+const CACHE_MAX: usize = 2 * CACHE_BATCH;
+
+// Pages of the process-wide pools that this thread has taken out of the
+// arenas and nobody is using, by slot, so most takes and drops touch
+// nothing shared. A page dropped on another thread just goes into that
+// thread's cache instead.
+struct Cache(Vec<Vec<Block>>);
+
+impl Cache {
+    fn slot(&mut self, slot: usize) -> &mut Vec<Block> {
+        if self.0.len() <= slot { self.0.resize_with(slot + 1, Vec::new); }
+        &mut self.0[slot]
+    }
+}
+
+impl Drop for Cache {
+    fn drop(&mut self) {
+        for (slot, blocks) in self.0.iter_mut().enumerate() {
+            spill(&SHARED[slot], blocks.drain(..));
+        }
+    }
+}
+
+thread_local! {
+    static CACHE: RefCell<Cache> = const { RefCell::new(Cache(Vec::new())) };
+}
+
+fn exhausted() -> Error {
+    Error::new(ErrorKind::WouldBlock, "buffer pool is at its page limit")
+}
+
+impl Pool {
+    fn new(config: PoolConfig, slot: Option<usize>) -> Arc<Pool> {
+        Arc::new(Pool {
+            config,
+            slot,
+            available: ConcurrentQueue::unbounded(),
+            arenas: Mutex::new(Vec::new()),
+            in_use: AtomicUsize::new(0),
+            mapped: AtomicUsize::new(0),
+            waiters: ConcurrentQueue::unbounded(),
+            alloc_failures: AtomicU64::new(0),
+            mmap_calls: AtomicU64::new(0),
+            munmap_calls: AtomicU64::new(0),
+        })
+    }
+
+    pub(crate) fn default_pool() -> &'static Arc<Pool> {
+        &SHARED[0]
+    }
+
+    pub(crate) fn on_node(node: NumaNode) -> &'static Arc<Pool> {
+        &SHARED[node.id() as usize + 1]
+    }
+
+    pub(crate) fn is_default(&self) -> bool {
+        self.slot == Some(0)
+    }
+
+    // Counts `pages` as in use, if that keeps us within the limit.
+    fn reserve(&self, pages: usize) -> bool {
+        let mut in_use = self.in_use.load(Ordering::SeqCst);
+        loop {
+            match in_use.checked_add(pages) {
+                Some(want) if want <= self.config.max_pages => {
+                    match self.in_use.compare_exchange_weak(in_use, want, Ordering::SeqCst, Ordering::SeqCst) {
+                        Ok(_) => { return true; }
+                        Err(now) => { in_use = now; }
+                    }
+                }
+                _ => { return false; }
+            }
+        }
+    }
+
+    fn unreserve(&self, pages: usize) {
+        self.in_use.fetch_sub(pages, Ordering::SeqCst);
+        while let Ok(waker) = self.waiters.pop() {
+            waker.wake();
+        }
+    }
+
+    pub(crate) fn page(self: &Arc<Pool>) -> Result<Block, Error> {
+        self.take_page().map_err(|e| self.failed(e))
+    }
+
+    // Process-wide pools go by way of this thread's cache, unless the
+    // thread is exiting and its cache is gone.
+    fn take_page(self: &Arc<Pool>) -> Result<Block, Error> {
+        if let Some(slot) = self.slot {
+            let cached = CACHE.try_with(|cache| {
+                let mut cache = cache.borrow_mut();
+                let cache = cache.slot(slot);
+                if cache.is_empty() { self.refill(cache)?; }
+                Ok(cache.pop().unwrap())
+            });
+            if let Ok(result) = cached { return result; }
+        }
+        if !self.reserve(1) { return Err(exhausted()); }
+        self.block().inspect_err(|_| self.unreserve(1))
+    }
+
+    // The caller must have reserved it.
+    fn block(self: &Arc<Pool>) -> Result<Block, Error> {
+        let mut blocks = Vec::with_capacity(1);
+        self.blocks(1, &mut blocks)?;
+        Ok(blocks.pop().unwrap())
+    }
+
+    // Pages from as few arenas as we can, mapping a new one whenever
+    // they're all full. The caller must have reserved them, and gets back
+    // any that were taken before an error.
+    fn blocks(self: &Arc<Pool>, mut pages: usize, into: &mut Vec<Block>) -> Result<(), Error> {
+        while pages > 0 {
+            // Whoever maps an arena holds the lock until it's listed, so
+            // that the others waiting on it find that one rather than map
+            // their own.
+            let (arena, _growing) = match self.available.pop() {
+                Ok(arena) => (arena, None),
+                Err(_) => {
+                    let mut arenas = self.arenas.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                    match self.available.pop() {
+                        Ok(arena) => (arena, None),
+                        Err(_) => {
+                            self.mmap_calls.fetch_add(1, Ordering::Relaxed);
+                            let arena = Arena::new(Arc::downgrade(self), self.config.node, self.slot, self.config.max_pages)?;
+                            self.mapped.fetch_add(arena.pages(), Ordering::SeqCst);
+                            arenas.push(arena.clone());
+                            (arena, Some(arenas))
+                        }
+                    }
+                }
+            };
+            while pages > 0 {
+                match arena.take() {
+                    Some(block) => {
+                        into.push(block);
+                        pages -= 1;
+                    }
+                    None => break,
+                }
+            }
+            arena.relist(&self.available);
+        }
+        Ok(())
+    }
+
+    // Tops up an empty cache. Reserved pages the arenas couldn't give us
+    // are unreserved, and it's only an error if we got none at all.
+    fn refill(self: &Arc<Pool>, cache: &mut Vec<Block>) -> Result<(), Error> {
+        if !self.reserve(CACHE_BATCH) { return Err(exhausted()); }
+        let result = self.blocks(CACHE_BATCH, cache);
+        if cache.len() < CACHE_BATCH { self.unreserve(CACHE_BATCH - cache.len()); }
+        if cache.is_empty() { result } else { Ok(()) }
+    }
+
+    // Once idle pages pass the high watermark, unmaps unused arenas while
+    // that keeps them at or above the low one. Whoever is already at it
+    // can finish the job.
+    fn trim(&self) {
+        if self.idle() <= self.config.high_watermark { return; }
+        let mut arenas = match self.arenas.try_lock() {
+            Ok(arenas) => arenas,
+            Err(_) => { return; }
+        };
+        arenas.retain(|arena| {
+            let keep = self.config.low_watermark.saturating_add(arena.pages());
+            if self.idle() < keep || !arena.is_unused() { return true; }
+            if !arena.retire(&self.available) { return true; }
+            self.munmap_calls.fetch_add(1, Ordering::Relaxed);
+            self.mapped.fetch_sub(arena.pages(), Ordering::SeqCst);
+            false
+        });
+    }
+
+    fn failed(&self, e: Error) -> Error {
+        self.alloc_failures.fetch_add(1, Ordering::Relaxed);
+        e
+    }
+
+    pub(crate) fn stats(&self) -> PoolStats {
+        let mapped_pages = self.mapped.load(Ordering::SeqCst);
+        let in_use_pages = self.in_use.load(Ordering::SeqCst);
+        PoolStats {
+            mapped_pages,
+            free_pages: min(mapped_pages, self.config.max_pages).saturating_sub(in_use_pages),
+            in_use_pages,
+            alloc_failures: self.alloc_failures.load(Ordering::Relaxed),
+            mmap_calls: self.mmap_calls.load(Ordering::Relaxed),
+            munmap_calls: self.munmap_calls.load(Ordering::Relaxed),
+        }
+    }
+
+    // All the process-wide pools together.
+    pub(crate) fn shared_stats() -> PoolStats {
+        SHARED.iter().fold(PoolStats::default(), |stats, pool| stats.add(pool.stats()))
+    }
+
+    fn idle(&self) -> usize {
+        self.mapped.load(Ordering::SeqCst).saturating_sub(self.in_use.load(Ordering::SeqCst))
+    }
+}
+
+// Pages of the process-wide pools go into this thread's cache, which
+// gives a batch back to the arenas once it's full. The rest go straight
+// back.
+pub(crate) fn release(block: Block) {
+    if let Some(slot) = block.arena.slot {
+        let mut block = Some(block);
+        #[allow(unused_must_use)]
+        {
+            CACHE.try_with(|cache| {
+                let mut cache = cache.borrow_mut();
+                let cache = cache.slot(slot);
+                cache.extend(block.take());
+                if cache.len() > CACHE_MAX {
+                    let keep = cache.len() - CACHE_BATCH;
+                    spill(&SHARED[slot], cache.drain(keep..));
+                }
+            });
+        }
+        if let Some(block) = block {
+            spill(&SHARED[slot], iter::once(block));
+        }
+    } else if let Some(pool) = block.arena.pool.upgrade() {
+        spill(&pool, iter::once(block));
+    } else {
+        Arena::put(block);
+    }
+}
+
+// Returns pages to their arenas, and the arenas to the pool's available
+// queue if they had dropped out.
+fn spill(pool: &Pool, blocks: impl Iterator<Item = Block>) {
+    let mut pages = 0;
+    for block in blocks {
+        Arena::put(block).list(&pool.available);
+        pages += 1;
+    }
+    if pages > 0 {
+        pool.unreserve(pages);
+        pool.trim();
+    }
+}
+
+// A pool of pages with a limit on how many it hands out. Buffers taken
+// from it draw on it when they grow too, failing with WouldBlock if that
+// would go over the limit.
+#[derive(Clone)]
+pub struct BufferPool(Arc<Pool>);
+
+impl BufferPool {
+    pub fn new(config: PoolConfig) -> Result<BufferPool, Error> {
+        if config.low_watermark > config.high_watermark {
+            return Err(Error::new(ErrorKind::InvalidInput, "low watermark is above the high watermark"));
+        }
+        Ok(BufferPool(Pool::new(config, None)))
+    }
+
+    // Waits until `pages` pages can be had without going over the limit.
+    pub async fn acquire(&self, pages: usize) -> Result<Buffer, Error> {
+        if pages > self.0.config.max_pages {
+            return Err(self.0.failed(Error::new(ErrorKind::InvalidInput, "more pages than the pool's limit")));
+        }
+        Reserve { pool: &self.0, pages }.await;
+        Buffer::reserved(&self.0, pages).map_err(|e| self.0.failed(e))
+    }
+
+    // Like acquire, but fails with WouldBlock rather than wait.
+    pub fn try_acquire(&self, pages: usize) -> Result<Buffer, Error> {
+        if !self.0.reserve(pages) { return Err(self.0.failed(exhausted())); }
+        Buffer::reserved(&self.0, pages).map_err(|e| self.0.failed(e))
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        self.0.stats()
+    }
+}
+
+impl Buffer {
+    // A Buffer of `pages` pages already reserved from `pool`.
+    fn reserved(pool: &Arc<Pool>, pages: usize) -> Result<Buffer, Error> {
+        let mut buffer = Buffer::in_pool(PoolRef::Owned(pool.clone()));
+        for taken in 0..pages {
+            match pool.block() {
+                Ok(block) => buffer.push_block(block),
+                Err(e) => {
+                    // The ones we got are given back as the buffer drops.
+                    pool.unreserve(pages - taken);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(buffer)
+    }
+}
+
+// The pool a Buffer grows from. The process-wide ones are borrowed so
+// that passing them around doesn't contend on their reference counts.
+#[derive(Clone)]
+pub(crate) enum PoolRef {
+    Shared(&'static Arc<Pool>),
+    Owned(Arc<Pool>),
+}
+
+impl Deref for PoolRef {
+    type Target = Arc<Pool>;
+    fn deref(&self) -> &Arc<Pool> {
+        match self {
+            PoolRef::Shared(pool) => pool,
+            PoolRef::Owned(pool) => pool,
+        }
+    }
+}
+
+struct Reserve<'a> {
+    pool: &'a Pool,
+    pages: usize,
+}
+
+impl Future for Reserve<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.pool.reserve(self.pages) { return Poll::Ready(()); }
+        #[allow(unused_must_use)]
+        { self.pool.waiters.push(cx.waker().clone()); }
+        // Pages that came back before we were queued won't wake us.
+        if self.pool.reserve(self.pages) { return Poll::Ready(()); }
+        Poll::Pending
+    }
+}
@@ -0,0 +1,644 @@
+// Events that ringbahn doesn't have a shape for, mostly because it wants
+// to own the buffers as boxed slices and ours live in pages.
+
+use crate::buffer::{page_size, Buffer, Readable, Writeable};
+use crate::fs;
+use crate::io::OwnedBuf;
+use crate::net::SockAddr;
+use crate::unix::{received_fds, Control};
+use ringbahn::event::Event;
+use ringbahn::iou::sqe::{FsyncFlags, SubmissionFlags, SQE, SQEs};
+use ringbahn::ring::Cancellation;
+use super::sys;
+
+use std::ffi::CString;
+use std::io::{Error, IoSlice, IoSliceMut};
+use std::mem::ManuallyDrop;
+use std::os::unix::io::RawFd;
+use std::ptr;
+use std::slice;
+
+// The kernel rejects anything longer.
+const MAX_IOV: usize = libc::UIO_MAXIOV as usize;
+
+// libc::iovec rather than IoSlice because the slices point into pages
+// owned by the same struct. The pages sit at fixed addresses in arenas
+// that outlive the Buffer, so moving it around doesn't invalidate them.
+fn iovec(ptr: *const u8, len: usize) -> libc::iovec {
+    libc::iovec { iov_base: ptr as *mut libc::c_void, iov_len: len }
+}
+
+// Either a plain descriptor or a slot in the ring's registered file table.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Fd {
+    Raw(RawFd),
+    Fixed(u32),
+}
+
+impl Fd {
+    fn raw(self) -> RawFd {
+        match self {
+            Fd::Raw(fd) => fd,
+            Fd::Fixed(slot) => slot as RawFd,
+        }
+    }
+
+    // prep_* clears the flags, so these must be set afterwards.
+    fn flags(self) -> SubmissionFlags {
+        match self {
+            Fd::Raw(_) => SubmissionFlags::empty(),
+            Fd::Fixed(_) => SubmissionFlags::FIXED_FILE,
+        }
+    }
+}
+
+// There's no vectored form of READ_FIXED/WRITE_FIXED, so we only use them
+// when the whole operation lands in a single registered page.
+fn fixed_index(buffer: &Buffer, iovecs: &[libc::iovec], watermark: usize) -> Option<u16> {
+    if iovecs.len() == 1 { buffer.page_index(watermark / page_size()) } else { None }
+}
+
+pub(crate) struct ReadPages {
+    pub(crate) fd: Fd,
+    pub(crate) buffer: Buffer,
+    iovecs: Vec<libc::iovec>,
+    index: Option<u16>,
+    offset: u64,
+}
+
+impl ReadPages {
+    pub(crate) fn new(
+        fd: Fd, mut buffer: Buffer, high: usize, offset: usize, fixed: bool
+    ) -> Result<ReadPages, (Buffer, Error)> {
+        let iovecs: Vec<_> = match Writeable::spare(&mut buffer, high) {
+            Ok(spare) => spare.into_iter().take(MAX_IOV).map(|s| iovec(s.as_ptr(), s.len())).collect(),
+            Err(e) => { return Err((buffer, e)); }
+        };
+        let index = if fixed { fixed_index(&buffer, &iovecs, high) } else { None };
+        Ok(ReadPages { fd, buffer, iovecs, index, offset: offset as u64 })
+    }
+}
+
+impl Event for ReadPages {
+    fn sqes_needed() -> u32 { 1 }
+
+    unsafe fn prepare<'sq>(&mut self, sqs: &mut SQEs<'sq>) -> SQE<'sq> {
+        let mut sqe = sqs.single().unwrap();
+        if let Some(index) = self.index {
+            let buf = slice::from_raw_parts_mut(self.iovecs[0].iov_base.cast::<u8>(), self.iovecs[0].iov_len);
+            sqe.prep_read_fixed(self.fd.raw(), buf, self.offset, index as u32);
+        } else {
+            let bufs = slice::from_raw_parts_mut(self.iovecs.as_mut_ptr().cast::<IoSliceMut>(), self.iovecs.len());
+            sqe.prep_read_vectored(self.fd.raw(), bufs, self.offset);
+        }
+        sqe.set_flags(self.fd.flags());
+        sqe
+    }
+
+    fn cancel(this: ManuallyDrop<Self>) -> Cancellation {
+        Cancellation::from(Box::new(ManuallyDrop::into_inner(this)))
+    }
+}
+
+pub(crate) struct WritePages {
+    pub(crate) fd: Fd,
+    pub(crate) buffer: Buffer,
+    iovecs: Vec<libc::iovec>,
+    index: Option<u16>,
+    offset: u64,
+}
+
+impl WritePages {
+    pub(crate) fn new(
+        fd: Fd, mut buffer: Buffer, low: usize, high: usize, offset: usize, fixed: bool
+    ) -> WritePages {
+        let iovecs: Vec<_> = Readable::new(&mut buffer, low, high - low)
+            .take(MAX_IOV)
+            .map(|s| iovec(s.as_ptr(), s.len()))
+            .collect();
+        let index = if fixed { fixed_index(&buffer, &iovecs, low) } else { None };
+        WritePages { fd, buffer, iovecs, index, offset: offset as u64 }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.iovecs.iter().all(|iov| iov.iov_len == 0)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.iovecs.iter().map(|iov| iov.iov_len).sum()
+    }
+
+    unsafe fn prep(&mut self, sqe: &mut SQE<'_>) {
+        if let Some(index) = self.index {
+            let buf = slice::from_raw_parts(self.iovecs[0].iov_base.cast::<u8>(), self.iovecs[0].iov_len);
+            sqe.prep_write_fixed(self.fd.raw(), buf, self.offset, index as u32);
+        } else {
+            let bufs = slice::from_raw_parts(self.iovecs.as_ptr().cast::<IoSlice>(), self.iovecs.len());
+            sqe.prep_write_vectored(self.fd.raw(), bufs, self.offset);
+        }
+        sqe.set_flags(self.fd.flags());
+    }
+}
+
+impl Event for WritePages {
+    fn sqes_needed() -> u32 { 1 }
+
+    unsafe fn prepare<'sq>(&mut self, sqs: &mut SQEs<'sq>) -> SQE<'sq> {
+        let mut sqe = sqs.single().unwrap();
+        self.prep(&mut sqe);
+        sqe
+    }
+
+    fn cancel(this: ManuallyDrop<Self>) -> Cancellation {
+        Cancellation::from(Box::new(ManuallyDrop::into_inner(this)))
+    }
+}
+
+pub(crate) struct Read<B> {
+    pub(crate) fd: Fd,
+    pub(crate) buf: B,
+    pub(crate) offset: u64,
+}
+
+impl<B: OwnedBuf> Event for Read<B> {
+    fn sqes_needed() -> u32 { 1 }
+
+    unsafe fn prepare<'sq>(&mut self, sqs: &mut SQEs<'sq>) -> SQE<'sq> {
+        let mut sqe = sqs.single().unwrap();
+        sqe.prep_read(self.fd.raw(), self.buf.as_mut(), self.offset);
+        sqe.set_flags(self.fd.flags());
+        sqe
+    }
+
+    fn cancel(this: ManuallyDrop<Self>) -> Cancellation {
+        Cancellation::from(Box::new(ManuallyDrop::into_inner(this).buf))
+    }
+}
+
+pub(crate) struct Write<B> {
+    pub(crate) fd: Fd,
+    pub(crate) buf: B,
+    pub(crate) offset: u64,
+}
+
+impl<B: OwnedBuf> Event for Write<B> {
+    fn sqes_needed() -> u32 { 1 }
+
+    unsafe fn prepare<'sq>(&mut self, sqs: &mut SQEs<'sq>) -> SQE<'sq> {
+        let mut sqe = sqs.single().unwrap();
+        sqe.prep_write(self.fd.raw(), self.buf.as_ref(), self.offset);
+        sqe.set_flags(self.fd.flags());
+        sqe
+    }
+
+    fn cancel(this: ManuallyDrop<Self>) -> Cancellation {
+        Cancellation::from(Box::new(ManuallyDrop::into_inner(this).buf))
+    }
+}
+
+pub(crate) struct Fsync {
+    pub(crate) fd: Fd,
+    pub(crate) flags: FsyncFlags,
+}
+
+impl Event for Fsync {
+    fn sqes_needed() -> u32 { 1 }
+
+    unsafe fn prepare<'sq>(&mut self, sqs: &mut SQEs<'sq>) -> SQE<'sq> {
+        let mut sqe = sqs.single().unwrap();
+        sqe.prep_fsync(self.fd.raw(), self.flags);
+        sqe.set_flags(self.fd.flags());
+        sqe
+    }
+}
+
+// A write linked to an fdatasync, so both go to the kernel in one
+// submission. Only the fsync's completion is reported: the kernel cancels
+// it (ECANCELED) if the write fails or comes up short, so success means
+// every byte was written and synced. The ring tests cover both outcomes.
+pub(crate) struct WriteSync {
+    pub(crate) write: WritePages,
+}
+
+impl Event for WriteSync {
+    fn sqes_needed() -> u32 { 2 }
+
+    unsafe fn prepare<'sq>(&mut self, sqs: &mut SQEs<'sq>) -> SQE<'sq> {
+        // The chain links all but its last SQE and leaves their user_data
+        // cleared, so the driver only completes us for the fsync.
+        let mut chain = sqs.soft_linked();
+        let mut write = chain.next().unwrap();
+        self.write.prep(&mut write);
+        write.set_flags(write.flags() | self.write.fd.flags());
+        let mut fsync = chain.next().unwrap();
+        fsync.prep_fsync(self.write.fd.raw(), FsyncFlags::FSYNC_DATASYNC);
+        fsync.set_flags(fsync.flags() | self.write.fd.flags());
+        fsync
+    }
+
+    fn cancel(this: ManuallyDrop<Self>) -> Cancellation {
+        Cancellation::from(Box::new(ManuallyDrop::into_inner(this)))
+    }
+}
+
+pub(crate) struct SyncRange {
+    pub(crate) fd: Fd,
+    pub(crate) offset: u64,
+    pub(crate) len: u32,
+    pub(crate) flags: u32,
+}
+
+impl Event for SyncRange {
+    fn sqes_needed() -> u32 { 1 }
+
+    unsafe fn prepare<'sq>(&mut self, sqs: &mut SQEs<'sq>) -> SQE<'sq> {
+        let mut sqe = sqs.single().unwrap();
+        sqe.prep_sync_file_range(self.fd.raw(), self.offset, self.len, self.flags);
+        sqe.set_flags(self.fd.flags());
+        sqe
+    }
+}
+
+// Offsets of -1 mean the descriptor's own position, which is what pipes
+// need.
+pub(crate) struct Splice {
+    pub(crate) from: RawFd,
+    pub(crate) from_off: i64,
+    pub(crate) to: RawFd,
+    pub(crate) to_off: i64,
+    pub(crate) len: u32,
+}
+
+impl Event for Splice {
+    fn sqes_needed() -> u32 { 1 }
+
+    unsafe fn prepare<'sq>(&mut self, sqs: &mut SQEs<'sq>) -> SQE<'sq> {
+        let mut sqe = sqs.single().unwrap();
+        sqe.prep_splice(self.from, self.from_off, self.to, self.to_off, self.len, libc::SPLICE_F_MOVE);
+        sqe
+    }
+}
+
+pub(crate) struct Tee {
+    pub(crate) from: RawFd,
+    pub(crate) to: RawFd,
+    pub(crate) len: u32,
+}
+
+impl Event for Tee {
+    fn sqes_needed() -> u32 { 1 }
+
+    unsafe fn prepare<'sq>(&mut self, sqs: &mut SQEs<'sq>) -> SQE<'sq> {
+        let mut sqe = sqs.single().unwrap();
+        sqe.prep_tee(self.from, self.to, self.len, 0);
+        sqe
+    }
+}
+
+pub(crate) struct Statx {
+    pub(crate) path: CString,
+    pub(crate) mask: u32,
+    // Boxed so the kernel's pointer survives the event being moved.
+    pub(crate) buf: Box<fs::Statx>,
+}
+
+impl Event for Statx {
+    fn sqes_needed() -> u32 { 1 }
+
+    unsafe fn prepare<'sq>(&mut self, sqs: &mut SQEs<'sq>) -> SQE<'sq> {
+        let mut sqe = sqs.single().unwrap();
+        // Our Statx has the kernel's layout, which libc's is a prefix of.
+        let buf = &mut *(&mut *self.buf as *mut fs::Statx).cast::<libc::statx>();
+        sqe.prep_statx(libc::AT_FDCWD, &self.path, 0, self.mask, buf);
+        sqe
+    }
+
+    fn cancel(this: ManuallyDrop<Self>) -> Cancellation {
+        Cancellation::from(Box::new(ManuallyDrop::into_inner(this)))
+    }
+}
+
+// The path operations, relative to the current directory. iou can't
+// prepare any of them, so they go through sys::prep_rw.
+pub(crate) enum PathOp {
+    Rename { from: CString, to: CString, flags: u32 },
+    // With `dir`, removes a directory instead, like rmdir.
+    Unlink { path: CString, dir: bool },
+    Mkdir { path: CString, mode: u32 },
+    Symlink { target: CString, link: CString },
+    Link { from: CString, to: CString },
+}
+
+impl PathOp {
+    pub(crate) fn opcode(&self) -> u8 {
+        match self {
+            PathOp::Rename { .. } => sys::IORING_OP_RENAMEAT,
+            PathOp::Unlink { .. } => sys::IORING_OP_UNLINKAT,
+            PathOp::Mkdir { .. } => sys::IORING_OP_MKDIRAT,
+            PathOp::Symlink { .. } => sys::IORING_OP_SYMLINKAT,
+            PathOp::Link { .. } => sys::IORING_OP_LINKAT,
+        }
+    }
+}
+
+impl Event for PathOp {
+    fn sqes_needed() -> u32 { 1 }
+
+    unsafe fn prepare<'sq>(&mut self, sqs: &mut SQEs<'sq>) -> SQE<'sq> {
+        let mut sqe = sqs.single().unwrap();
+        let cwd = libc::AT_FDCWD;
+        // The second path goes where the offset would, and the second
+        // directory where the length would.
+        let (addr, len, addr2, flags) = match self {
+            PathOp::Rename { from, to, flags } => (from.as_ptr(), cwd as u32, to.as_ptr(), *flags),
+            PathOp::Unlink { path, dir } => {
+                (path.as_ptr(), 0, ptr::null(), if *dir { libc::AT_REMOVEDIR as u32 } else { 0 })
+            }
+            PathOp::Mkdir { path, mode } => (path.as_ptr(), *mode, ptr::null(), 0),
+            PathOp::Symlink { target, link } => (target.as_ptr(), 0, link.as_ptr(), 0),
+            PathOp::Link { from, to } => (from.as_ptr(), cwd as u32, to.as_ptr(), 0),
+        };
+        let raw = sqe.raw_mut() as *mut _ as *mut sys::RawSqe;
+        sys::prep_rw(raw, self.opcode(), cwd, addr as u64, len, addr2 as u64, flags);
+        sqe
+    }
+
+    fn cancel(this: ManuallyDrop<Self>) -> Cancellation {
+        Cancellation::from(Box::new(ManuallyDrop::into_inner(this)))
+    }
+}
+
+// Socket addresses are boxed by the events so they stay put if the event
+// is moved into a cancellation.
+pub(crate) struct Accept {
+    pub(crate) fd: Fd,
+    pub(crate) addr: Box<SockAddr>,
+}
+
+// ringbahn drops the result of a cancelled event, so a connection that
+// lands after the accept was dropped goes unclosed. Incoming accepts on
+// the ring in multishot.rs where it can, which closes them.
+impl Event for Accept {
+    fn sqes_needed() -> u32 { 1 }
+
+    unsafe fn prepare<'sq>(&mut self, sqs: &mut SQEs<'sq>) -> SQE<'sq> {
+        let mut sqe = sqs.single().unwrap();
+        let addr = (&mut self.addr.storage as *mut libc::sockaddr_storage).cast();
+        sqe.prep_accept(self.fd.raw(), addr, &mut self.addr.len, libc::SOCK_CLOEXEC);
+        sqe.set_flags(self.fd.flags());
+        sqe
+    }
+
+    fn cancel(this: ManuallyDrop<Self>) -> Cancellation {
+        Cancellation::from(ManuallyDrop::into_inner(this).addr)
+    }
+}
+
+pub(crate) struct Connect {
+    pub(crate) fd: Fd,
+    pub(crate) addr: Box<SockAddr>,
+}
+
+impl Event for Connect {
+    fn sqes_needed() -> u32 { 1 }
+
+    unsafe fn prepare<'sq>(&mut self, sqs: &mut SQEs<'sq>) -> SQE<'sq> {
+        let mut sqe = sqs.single().unwrap();
+        let addr = (&self.addr.storage as *const libc::sockaddr_storage).cast();
+        sqe.prep_connect(self.fd.raw(), addr, self.addr.len);
+        sqe.set_flags(self.fd.flags());
+        sqe
+    }
+
+    fn cancel(this: ManuallyDrop<Self>) -> Cancellation {
+        Cancellation::from(ManuallyDrop::into_inner(this).addr)
+    }
+}
+
+pub(crate) struct Recv<B> {
+    pub(crate) fd: Fd,
+    pub(crate) buf: B,
+}
+
+impl<B: OwnedBuf> Event for Recv<B> {
+    fn sqes_needed() -> u32 { 1 }
+
+    unsafe fn prepare<'sq>(&mut self, sqs: &mut SQEs<'sq>) -> SQE<'sq> {
+        let mut sqe = sqs.single().unwrap();
+        sqe.prep_recv(self.fd.raw(), self.buf.as_mut(), 0);
+        sqe.set_flags(self.fd.flags());
+        sqe
+    }
+
+    fn cancel(this: ManuallyDrop<Self>) -> Cancellation {
+        Cancellation::from(Box::new(ManuallyDrop::into_inner(this).buf))
+    }
+}
+
+pub(crate) struct Send<B> {
+    pub(crate) fd: Fd,
+    pub(crate) buf: B,
+}
+
+impl<B: OwnedBuf> Event for Send<B> {
+    fn sqes_needed() -> u32 { 1 }
+
+    unsafe fn prepare<'sq>(&mut self, sqs: &mut SQEs<'sq>) -> SQE<'sq> {
+        let mut sqe = sqs.single().unwrap();
+        sqe.prep_send(self.fd.raw(), self.buf.as_ref(), libc::MSG_NOSIGNAL);
+        sqe.set_flags(self.fd.flags());
+        sqe
+    }
+
+    fn cancel(this: ManuallyDrop<Self>) -> Cancellation {
+        Cancellation::from(Box::new(ManuallyDrop::into_inner(this).buf))
+    }
+}
+
+// Paged socket I/O goes through sendmsg/recvmsg, the only vectored socket
+// ops; the msghdr is boxed for the same reason as SockAddr.
+fn msghdr(iovecs: &mut [libc::iovec]) -> Box<libc::msghdr> {
+    let mut msg: Box<libc::msghdr> = Box::new(unsafe { std::mem::zeroed() });
+    msg.msg_iov = iovecs.as_mut_ptr();
+    msg.msg_iovlen = iovecs.len() as _;
+    msg
+}
+
+pub(crate) struct RecvPages {
+    pub(crate) fd: Fd,
+    pub(crate) buffer: Buffer,
+    // Only reached through msg, which points into it.
+    #[allow(dead_code)]
+    iovecs: Vec<libc::iovec>,
+    msg: Box<libc::msghdr>,
+}
+
+impl RecvPages {
+    pub(crate) fn new(fd: Fd, mut buffer: Buffer, high: usize) -> Result<RecvPages, (Buffer, Error)> {
+        let mut iovecs: Vec<_> = match Writeable::spare(&mut buffer, high) {
+            Ok(spare) => spare.into_iter().take(MAX_IOV).map(|s| iovec(s.as_ptr(), s.len())).collect(),
+            Err(e) => { return Err((buffer, e)); }
+        };
+        let msg = msghdr(&mut iovecs);
+        Ok(RecvPages { fd, buffer, iovecs, msg })
+    }
+}
+
+impl Event for RecvPages {
+    fn sqes_needed() -> u32 { 1 }
+
+    unsafe fn prepare<'sq>(&mut self, sqs: &mut SQEs<'sq>) -> SQE<'sq> {
+        let mut sqe = sqs.single().unwrap();
+        sqe.prep_recvmsg(self.fd.raw(), &mut *self.msg, 0);
+        sqe.set_flags(self.fd.flags());
+        sqe
+    }
+
+    fn cancel(this: ManuallyDrop<Self>) -> Cancellation {
+        Cancellation::from(Box::new(ManuallyDrop::into_inner(this)))
+    }
+}
+
+pub(crate) struct SendPages {
+    pub(crate) fd: Fd,
+    pub(crate) buffer: Buffer,
+    iovecs: Vec<libc::iovec>,
+    msg: Box<libc::msghdr>,
+}
+
+impl SendPages {
+    pub(crate) fn new(fd: Fd, mut buffer: Buffer, low: usize, high: usize) -> SendPages {
+        let mut iovecs: Vec<_> = Readable::new(&mut buffer, low, high - low)
+            .take(MAX_IOV)
+            .map(|s| iovec(s.as_ptr(), s.len()))
+            .collect();
+        let msg = msghdr(&mut iovecs);
+        SendPages { fd, buffer, iovecs, msg }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.iovecs.iter().all(|iov| iov.iov_len == 0)
+    }
+}
+
+impl Event for SendPages {
+    fn sqes_needed() -> u32 { 1 }
+
+    unsafe fn prepare<'sq>(&mut self, sqs: &mut SQEs<'sq>) -> SQE<'sq> {
+        let mut sqe = sqs.single().unwrap();
+        sqe.prep_sendmsg(self.fd.raw(), &*self.msg, libc::MSG_NOSIGNAL as u32);
+        sqe.set_flags(self.fd.flags());
+        sqe
+    }
+
+    fn cancel(this: ManuallyDrop<Self>) -> Cancellation {
+        Cancellation::from(Box::new(ManuallyDrop::into_inner(this)))
+    }
+}
+
+// What the kernel reads (and for recvmsg, writes) besides the data. The
+// msghdr points at the rest, so they share a box.
+pub(crate) struct MsgParts {
+    msg: libc::msghdr,
+    iov: libc::iovec,
+    pub(crate) addr: SockAddr,
+    control: Control,
+}
+
+impl MsgParts {
+    // Addressed, for unconnected datagram sockets.
+    pub(crate) fn new(addr: SockAddr) -> Box<MsgParts> {
+        let mut parts = MsgParts::unnamed(Control::none());
+        parts.addr = addr;
+        parts.msg.msg_name = (&mut parts.addr.storage as *mut libc::sockaddr_storage).cast();
+        parts.msg.msg_namelen = parts.addr.len;
+        parts
+    }
+
+    // For connected sockets, which may refuse an address, with ancillary
+    // data attached.
+    pub(crate) fn unnamed(control: Control) -> Box<MsgParts> {
+        let mut parts = Box::new(MsgParts {
+            msg: unsafe { std::mem::zeroed() },
+            iov: iovec(std::ptr::null(), 0),
+            addr: SockAddr::empty(),
+            control,
+        });
+        parts.msg.msg_iov = &mut parts.iov;
+        parts.msg.msg_iovlen = 1;
+        let MsgParts { msg, control, .. } = &mut *parts;
+        control.attach(msg);
+        parts
+    }
+
+    // After a recvmsg: the descriptors that came with the data.
+    pub(crate) fn received_fds(&self) -> Result<Vec<std::fs::File>, Error> {
+        unsafe { received_fds(&self.msg) }
+    }
+
+    // After a recvmsg: the sender, and whether its datagram was cut short.
+    pub(crate) fn received(&mut self) -> (bool, &SockAddr) {
+        self.addr.len = self.msg.msg_namelen;
+        (self.msg.msg_flags & libc::MSG_TRUNC != 0, &self.addr)
+    }
+}
+
+pub(crate) struct RecvMsg<B> {
+    pub(crate) fd: Fd,
+    pub(crate) buf: B,
+    pub(crate) parts: Box<MsgParts>,
+    pub(crate) flags: i32,
+}
+
+impl<B: OwnedBuf> Event for RecvMsg<B> {
+    fn sqes_needed() -> u32 { 1 }
+
+    unsafe fn prepare<'sq>(&mut self, sqs: &mut SQEs<'sq>) -> SQE<'sq> {
+        let mut sqe = sqs.single().unwrap();
+        let buf = self.buf.as_mut();
+        self.parts.iov = iovec(buf.as_ptr(), buf.len());
+        sqe.prep_recvmsg(self.fd.raw(), &mut self.parts.msg, self.flags as u32);
+        sqe.set_flags(self.fd.flags());
+        sqe
+    }
+
+    fn cancel(this: ManuallyDrop<Self>) -> Cancellation {
+        let this = ManuallyDrop::into_inner(this);
+        Cancellation::from(Box::new(Unclaimed(this.buf, this.parts)))
+    }
+}
+
+// A cancelled recvmsg's buffers, dropped once the kernel is done with
+// them. Descriptors it received by then have nobody to take them, so they
+// are closed.
+struct Unclaimed<B>(B, Box<MsgParts>);
+
+impl<B> Drop for Unclaimed<B> {
+    fn drop(&mut self) {
+        drop(self.1.received_fds());
+    }
+}
+
+pub(crate) struct SendMsg<B> {
+    pub(crate) fd: Fd,
+    pub(crate) buf: B,
+    pub(crate) len: usize,
+    pub(crate) parts: Box<MsgParts>,
+}
+
+impl<B: OwnedBuf> Event for SendMsg<B> {
+    fn sqes_needed() -> u32 { 1 }
+
+    unsafe fn prepare<'sq>(&mut self, sqs: &mut SQEs<'sq>) -> SQE<'sq> {
+        let mut sqe = sqs.single().unwrap();
+        self.parts.iov = iovec(self.buf.as_ref().as_ptr(), self.len);
+        sqe.prep_sendmsg(self.fd.raw(), &self.parts.msg, libc::MSG_NOSIGNAL as u32);
+        sqe.set_flags(self.fd.flags());
+        sqe
+    }
+
+    fn cancel(this: ManuallyDrop<Self>) -> Cancellation {
+        let this = ManuallyDrop::into_inner(this);
+        Cancellation::from(Box::new((this.buf, this.parts)))
+    }
+}
@@ -0,0 +1,62 @@
+use concurrent_queue::ConcurrentQueue;
+use libc::c_uint;
+use super::sys::{self, FilesUpdate};
+
+use std::io::{Error, ErrorKind};
+use std::os::unix::io::RawFd;
+
+// The kernel's registered file table for a ring and the slots in it that
+// nobody is using. Slots start out empty (-1) and are filled and emptied
+// one at a time with FILES_UPDATE.
+pub(crate) struct FileTable {
+    ring: RawFd,
+    free: ConcurrentQueue<u32>,
+}
+
+impl FileTable {
+    pub(crate) fn register(ring: RawFd, slots: u32) -> Result<FileTable, Error> {
+        if slots == 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "file table needs at least one slot"));
+        }
+        let mut fds: Vec<RawFd> = vec![-1; slots as usize];
+        sys::register(ring, sys::IORING_REGISTER_FILES, fds.as_mut_ptr().cast(), slots as c_uint)?;
+        let free = ConcurrentQueue::unbounded();
+        for slot in 0..slots {
+            #[allow(unused_must_use)]
+            { free.push(slot); }
+        }
+        Ok(FileTable { ring, free })
+    }
+
+    // Puts `fd` into a free slot and returns it, or None if the table is full.
+    pub(crate) fn insert(&self, fd: RawFd) -> Result<Option<u32>, Error> {
+        let slot = match self.free.pop() {
+            Ok(slot) => slot,
+            Err(_) => { return Ok(None); }
+        };
+        match self.update(slot, fd) {
+            Ok(()) => Ok(Some(slot)),
+            Err(e) => {
+                self.release(slot);
+                Err(e)
+            }
+        }
+    }
+
+    pub(crate) fn remove(&self, slot: u32) -> Result<(), Error> {
+        self.update(slot, -1)?;
+        self.release(slot);
+        Ok(())
+    }
+
+    #[allow(unused_must_use)]
+    fn release(&self, slot: u32) {
+        self.free.push(slot);
+    }
+
+    fn update(&self, slot: u32, fd: RawFd) -> Result<(), Error> {
+        let mut fds = [fd];
+        let mut update = FilesUpdate { offset: slot, resv: 0, fds: fds.as_mut_ptr() as u64 };
+        sys::register(self.ring, sys::IORING_REGISTER_FILES_UPDATE, (&mut update as *mut FilesUpdate).cast(), 1)
+    }
+}
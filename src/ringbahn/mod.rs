@@ -1,27 +1,493 @@
+use blocking::unblock;
+use crate::buffer::{self, Buffer};
+use crate::direct::{self, DirectAlign};
+use crate::fs::{self as backplane_fs, RenameFlags, Statx, SyncRangeFlags};
+use crate::io::OwnedBuf;
+use crate::splice;
+use libc::c_uint;
 use maglev::Driver;
-use ringbahn::fs::{self, AsyncWriteExt};
-use std::io::{IoSlice, IoSliceMut, Result, SeekFrom};
+use once_cell::sync::OnceCell;
+use ringbahn::fs;
+use ringbahn::iou::sqe::FsyncFlags;
+use ringbahn::Submission;
+use std::cmp::min;
+use std::io::{Error, ErrorKind, Result};
+use std::ops::Range;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::Path;
-use std::pin::Pin;
-use std::task::{Context, Poll};
+use std::sync::Arc;
 
-#[derive(Clone, Default)]
+mod event;
+mod files;
+mod multishot;
+mod net;
+mod pipes;
+mod sys;
+mod unix;
+pub use net::{Incoming, RecvStream, TcpListener, TcpStream, UdpSocket};
+pub use unix::{UnixDatagram, UnixListener, UnixStream};
+use event::{Fd, Fsync, PathOp, Read, ReadPages, Splice, SyncRange, Tee, Write, WritePages, WriteSync};
+use files::FileTable;
+use pipes::Pipe;
+use sys::Probe;
+
+// The opcodes we can't do without. Anything optional is checked against
+// the probe at the point of use.
+const REQUIRED_OPS: &[u8] = &[
+    sys::IORING_OP_READV,
+    sys::IORING_OP_WRITEV,
+    sys::IORING_OP_FSYNC,
+    sys::IORING_OP_OPENAT,
+    sys::IORING_OP_CLOSE,
+    sys::IORING_OP_READ,
+    sys::IORING_OP_WRITE,
+];
+
+#[derive(Clone)]
 pub struct IO {
     driver: Driver,
+    probe: Probe,
+    files: Arc<OnceCell<FileTable>>,
+}
+
+pub struct File {
+    file: fs::File<Driver>,
+    driver: Driver,
+    // The owning IO's, for the optional opcodes.
+    probe: Probe,
+    files: Arc<OnceCell<FileTable>>,
+    slot: Option<u32>,
+    direct: Option<DirectAlign>,
 }
 
-pub struct File(fs::File<Driver>);
+// Registration is per ring, so this is what we key registered resources on.
+fn ring_fd(driver: &Driver) -> RawFd {
+    driver.as_raw_fd()
+}
 
 impl IO {
-    pub async fn create_file(&mut self, path: impl AsRef<Path>) -> Result<File> {
-        Ok(File(fs::File::create_on_driver(path, self.driver.clone()).await?))
+    // Returns None if io_uring is unavailable on this host (old kernel,
+    // seccomp, the io_uring_disabled sysctl) or lacks an opcode we need.
+    pub fn probe() -> Option<IO> {
+        let probe = sys::probed()?;
+        if REQUIRED_OPS.iter().all(|op| probe.supports(*op)) {
+            Some(IO { driver: Driver::default(), probe, files: Arc::new(OnceCell::new()) })
+        } else {
+            None
+        }
+    }
+
+    // Pins `pages` pages with the kernel up front. Buffers draw on them
+    // before the general free list, and single-page reads and writes on
+    // them are submitted as READ_FIXED/WRITE_FIXED.
+    pub fn register_buffers(&self, pages: usize) -> Result<()> {
+        if !self.probe.supports(sys::IORING_OP_READ_FIXED) || !self.probe.supports(sys::IORING_OP_WRITE_FIXED) {
+            return Err(Error::new(ErrorKind::Other, "kernel does not support fixed buffers"));
+        }
+        let ring = ring_fd(&self.driver);
+        buffer::register_pages(ring, pages, |iovecs| {
+            sys::register(ring, sys::IORING_REGISTER_BUFFERS, iovecs.as_ptr() as *mut _, iovecs.len() as c_uint)
+        })
+    }
+
+    // Sets up a registered file table with room for `slots` files. From
+    // then on, files we open take a slot while there is one free and are
+    // submitted with IOSQE_FIXED_FILE, saving the kernel an fd lookup and
+    // refcount per operation. Closing a file frees its slot.
+    pub fn register_files(&self, slots: u32) -> Result<()> {
+        let table = FileTable::register(ring_fd(&self.driver), slots)?;
+        self.files.set(table)
+            .map_err(|_| Error::new(ErrorKind::AlreadyExists, "file table already registered"))
+    }
+
+    pub async fn create_file(&self, path: impl AsRef<Path>) -> Result<File> {
+        let file = fs::File::create_on_driver(path, self.driver.clone()).await?;
+        Ok(self.wrap(file))
+    }
+
+    // ringbahn only knows how to open read-only, so anything with
+    // options is opened on the threadpool and handed to the driver.
+    pub async fn open_file(&self, path: impl AsRef<Path>, opts: &std::fs::OpenOptions) -> Result<File> {
+        let path = path.as_ref().to_owned();
+        let opts = opts.clone();
+        let file = unblock(move || opts.open(path)).await?;
+        Ok(self.from_file(file))
+    }
+
+    pub fn from_file(&self, file: std::fs::File) -> File {
+        self.wrap(fs::File::run_on_driver(file, self.driver.clone()))
+    }
+
+    // Native on kernels that have IORING_OP_STATX (5.6), otherwise on the
+    // threadpool like the rest of the fs operations.
+    pub async fn statx(&self, path: impl AsRef<Path>, mask: u32) -> Result<Statx> {
+        if !self.probe.supports(sys::IORING_OP_STATX) {
+            return backplane_fs::statx(path.as_ref().to_owned(), mask).await;
+        }
+        let path = backplane_fs::cstring(path.as_ref())?;
+        let event = event::Statx { path, mask, buf: Box::new(Statx::default()) };
+        let (event, res) = Submission::new(event, self.driver.clone()).await;
+        res?;
+        Ok(*event.buf)
+    }
+
+    // RENAMEAT and UNLINKAT arrived in 5.11, the rest in 5.15; older
+    // kernels get the threadpool.
+    pub async fn rename(&self, from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<()> {
+        self.rename_with(from, to, RenameFlags::empty()).await
+    }
+
+    pub async fn rename_with(&self, from: impl AsRef<Path>, to: impl AsRef<Path>, flags: RenameFlags) -> Result<()> {
+        let (from, to) = (from.as_ref(), to.as_ref());
+        if !self.probe.supports(sys::IORING_OP_RENAMEAT) {
+            return backplane_fs::rename_with(from.to_owned(), to.to_owned(), flags).await;
+        }
+        let (from, to) = (backplane_fs::cstring(from)?, backplane_fs::cstring(to)?);
+        self.path_op(PathOp::Rename { from, to, flags: flags.bits() }).await
+    }
+
+    pub async fn unlink(&self, path: impl AsRef<Path>) -> Result<()> {
+        if !self.probe.supports(sys::IORING_OP_UNLINKAT) {
+            return backplane_fs::unlink(path.as_ref().to_owned()).await;
+        }
+        self.path_op(PathOp::Unlink { path: backplane_fs::cstring(path.as_ref())?, dir: false }).await
+    }
+
+    pub async fn remove_dir(&self, path: impl AsRef<Path>) -> Result<()> {
+        if !self.probe.supports(sys::IORING_OP_UNLINKAT) {
+            return backplane_fs::remove_dir(path.as_ref().to_owned()).await;
+        }
+        self.path_op(PathOp::Unlink { path: backplane_fs::cstring(path.as_ref())?, dir: true }).await
+    }
+
+    pub async fn mkdir(&self, path: impl AsRef<Path>) -> Result<()> {
+        if !self.probe.supports(sys::IORING_OP_MKDIRAT) {
+            return backplane_fs::mkdir(path.as_ref().to_owned()).await;
+        }
+        self.path_op(PathOp::Mkdir { path: backplane_fs::cstring(path.as_ref())?, mode: 0o777 }).await
+    }
+
+    pub async fn hard_link(&self, src: impl AsRef<Path>, dst: impl AsRef<Path>) -> Result<()> {
+        let (src, dst) = (src.as_ref(), dst.as_ref());
+        if !self.probe.supports(sys::IORING_OP_LINKAT) {
+            return backplane_fs::hard_link(src.to_owned(), dst.to_owned()).await;
+        }
+        let (from, to) = (backplane_fs::cstring(src)?, backplane_fs::cstring(dst)?);
+        self.path_op(PathOp::Link { from, to }).await
+    }
+
+    // Creates `dst` pointing at `src`.
+    pub async fn symlink(&self, src: impl AsRef<Path>, dst: impl AsRef<Path>) -> Result<()> {
+        let (src, dst) = (src.as_ref(), dst.as_ref());
+        if !self.probe.supports(sys::IORING_OP_SYMLINKAT) {
+            return backplane_fs::symlink(src.to_owned(), dst.to_owned()).await;
+        }
+        let (target, link) = (backplane_fs::cstring(src)?, backplane_fs::cstring(dst)?);
+        self.path_op(PathOp::Symlink { target, link }).await
+    }
+
+    async fn path_op(&self, op: PathOp) -> Result<()> {
+        let (_, res) = Submission::new(op, self.driver.clone()).await;
+        res?;
+        Ok(())
+    }
+
+    // SPLICE and TEE arrived in 5.7 and 5.8; older kernels get the
+    // threadpool. Lengths past 32 bits are cut short, which both calls are
+    // allowed to be anyway.
+    pub async fn splice(
+        &self, from: RawFd, from_off: Option<u64>, to: RawFd, to_off: Option<u64>, len: usize
+    ) -> Result<usize> {
+        if !self.probe.supports(sys::IORING_OP_SPLICE) {
+            return unblock(move || splice::splice(from, from_off, to, to_off, len)).await;
+        }
+        let len = min(len, u32::MAX as usize) as u32;
+        Ok(splice_on(&self.driver, from, from_off, to, to_off, len).await? as usize)
+    }
+
+    pub async fn tee(&self, from: RawFd, to: RawFd, len: usize) -> Result<usize> {
+        if !self.probe.supports(sys::IORING_OP_TEE) {
+            return unblock(move || splice::tee(from, to, len)).await;
+        }
+        let event = Tee { from, to, len: min(len, u32::MAX as usize) as u32 };
+        Ok(Submission::new(event, self.driver.clone()).await.1? as usize)
+    }
+
+    fn wrap(&self, file: fs::File<Driver>) -> File {
+        let direct = direct::alignment(file.as_raw_fd());
+        let mut file = File {
+            file, driver: self.driver.clone(), probe: self.probe, files: self.files.clone(), slot: None, direct
+        };
+        // A full table just means this one goes unregistered.
+        #[allow(unused_must_use)]
+        { file.register(); }
+        file
+    }
+}
+
+async fn splice_on(
+    driver: &Driver, from: RawFd, from_off: Option<u64>, to: RawFd, to_off: Option<u64>, len: u32
+) -> Result<u32> {
+    let offset = |off: Option<u64>| off.map_or(-1, |off| off as i64);
+    let event = Splice { from, from_off: offset(from_off), to, to_off: offset(to_off), len };
+    Submission::new(event, driver.clone()).await.1
+}
+
+impl AsRawFd for File {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        #[allow(unused_must_use)]
+        { self.unregister(); }
+    }
+}
+
+impl File {
+    // Moves this file into the registered file table if there is one and
+    // it has a free slot. Returns whether the file is now registered.
+    pub fn register(&mut self) -> Result<bool> {
+        if self.slot.is_some() { return Ok(true); }
+        if let Some(table) = self.files.get() {
+            self.slot = table.insert(self.file.as_raw_fd())?;
+        }
+        Ok(self.slot.is_some())
+    }
+
+    // Takes this file out of the registered file table, freeing its slot.
+    pub fn unregister(&mut self) -> Result<()> {
+        if let (Some(slot), Some(table)) = (self.slot, self.files.get()) {
+            table.remove(slot)?;
+            self.slot = None;
+        }
+        Ok(())
+    }
+
+    pub fn is_registered(&self) -> bool {
+        self.slot.is_some()
+    }
+
+    pub fn direct_align(&self) -> Option<DirectAlign> {
+        self.direct
+    }
+
+    fn fd(&self) -> Fd {
+        match self.slot {
+            Some(slot) => Fd::Fixed(slot),
+            None => Fd::Raw(self.file.as_raw_fd()),
+        }
+    }
+
+    pub async fn read_at<B: OwnedBuf>(&self, buf: B, offset: usize) -> (B, Result<usize>) {
+        let event = Read { fd: self.fd(), buf, offset: offset as u64 };
+        let (event, read) = Submission::new(event, self.driver.clone()).await;
+        (event.buf, read.map(|read| read as usize))
+    }
+
+    pub async fn write_at<B: OwnedBuf>(&self, buf: B, offset: usize) -> (B, Result<usize>) {
+        let event = Write { fd: self.fd(), buf, offset: offset as u64 };
+        let (event, wrote) = Submission::new(event, self.driver.clone()).await;
+        (event.buf, wrote.map(|wrote| wrote as usize))
+    }
+
+    pub async fn sync_all(&self) -> Result<()> {
+        self.fsync(FsyncFlags::empty()).await
+    }
+
+    pub async fn sync_data(&self) -> Result<()> {
+        self.fsync(FsyncFlags::FSYNC_DATASYNC).await
+    }
+
+    // The SQE only has 32 bits for the length, so longer ranges (and
+    // kernels older than 5.2) go to the threadpool.
+    pub async fn sync_range(&self, offset: u64, len: u64, flags: SyncRangeFlags) -> Result<()> {
+        let native = self.probe.supports(sys::IORING_OP_SYNC_FILE_RANGE);
+        if native && len <= u32::MAX as u64 {
+            let event = SyncRange { fd: self.fd(), offset, len: len as u32, flags: flags.bits() };
+            Submission::new(event, self.driver.clone()).await.1?;
+            Ok(())
+        } else {
+            let fd = self.file.as_raw_fd();
+            unblock(move || backplane_fs::sync_range(fd, offset, len, flags)).await
+        }
+    }
+
+    // Sends `range` of the file to `to` through a pooled pipe: file into
+    // the pipe, pipe out to the socket, so the data never comes up to
+    // userspace. Stops early at the end of the file and returns how much
+    // was sent. Kernels without SPLICE use sendfile on the threadpool.
+    pub async fn send_to(&self, to: RawFd, range: Range<u64>) -> Result<u64> {
+        let from = self.file.as_raw_fd();
+        let len = range.end.saturating_sub(range.start);
+        if !self.probe.supports(sys::IORING_OP_SPLICE) {
+            return unblock(move || splice::sendfile(to, from, range.start, len)).await;
+        }
+        let pipe = Pipe::take()?;
+        // Once anything has gone out an error just cuts the transfer short,
+        // like a short write; the next call runs into it again.
+        let stop = |sent: u64, e: Error| if sent > 0 { Ok(sent) } else { Err(e) };
+        let mut sent = 0;
+        while sent < len {
+            let chunk = min(len - sent, pipe.capacity() as u64) as u32;
+            let fill = splice_on(&self.driver, from, Some(range.start + sent), pipe.write_fd(), None, chunk);
+            let filled = match fill.await {
+                Ok(filled) => filled,
+                Err(e) => { return stop(sent, e); }
+            };
+            if filled == 0 { break; }
+            // If this fails the pipe still has data in it, so it's dropped
+            // rather than given back.
+            let mut drained = 0;
+            while drained < filled {
+                match splice_on(&self.driver, pipe.read_fd(), None, to, None, filled - drained).await {
+                    Ok(0) => { return stop(sent, Error::from(ErrorKind::WriteZero)); }
+                    Ok(count) => {
+                        drained += count;
+                        sent += count as u64;
+                    }
+                    Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                    Err(e) => { return stop(sent, e); }
+                }
+            }
+        }
+        pipe.give_back();
+        Ok(sent)
+    }
+
+    fn fixed_buffers(&self) -> bool {
+        buffer::registered_ring() == Some(ring_fd(&self.driver))
+    }
+
+    pub(crate) async fn read_pages(&self, buf: Buffer, high: usize, offset: usize) -> (Buffer, Result<usize>) {
+        let event = match ReadPages::new(self.fd(), buf, high, offset, self.fixed_buffers()) {
+            Ok(event) => event,
+            Err((buf, e)) => { return (buf, Err(e)); }
+        };
+        let (event, read) = Submission::new(event, self.driver.clone()).await;
+        (event.buffer, read.map(|read| read as usize))
+    }
+
+    pub(crate) async fn write_pages(
+        &self, buf: Buffer, low: usize, high: usize, offset: usize, sync: bool
+    ) -> (Buffer, Result<usize>) {
+        let event = WritePages::new(self.fd(), buf, low, high, offset, self.fixed_buffers());
+        if event.is_empty() { return (event.buffer, Ok(0)); }
+        let (event, wrote) = Submission::new(event, self.driver.clone()).await;
+        let wrote = match wrote {
+            Ok(wrote) => wrote as usize,
+            Err(e) => { return (event.buffer, Err(e)); }
+        };
+        if sync {
+            if let Err(e) = self.fsync(FsyncFlags::FSYNC_DATASYNC).await {
+                return (event.buffer, Err(e));
+            }
+        }
+        (event.buffer, Ok(wrote))
+    }
+
+    pub(crate) async fn write_all_pages(
+        &self, buf: Buffer, low: usize, high: usize, offset: usize, sync: bool
+    ) -> (Buffer, Result<()>) {
+        let mut buf = buf;
+        if sync && low < high {
+            let write = WritePages::new(self.fd(), buf, low, high, offset, self.fixed_buffers());
+            // Linking only helps if one write can cover the whole range.
+            if write.len() == high - low {
+                let (event, res) = Submission::new(WriteSync { write }, self.driver.clone()).await;
+                match res {
+                    Ok(_) => { return (event.write.buffer, Ok(())); }
+                    // The write failed or was short; redo it step by step
+                    // below to find out which.
+                    Err(e) if e.raw_os_error() == Some(libc::ECANCELED) => {}
+                    Err(e) => { return (event.write.buffer, Err(e)); }
+                }
+                buf = event.write.buffer;
+            } else {
+                buf = write.buffer;
+            }
+        }
+        let mut wrote: usize = 0;
+        while low + wrote < high {
+            let event = WritePages::new(
+                self.fd(), buf, low + wrote, high, offset + wrote, self.fixed_buffers()
+            );
+            let (event, count) = Submission::new(event, self.driver.clone()).await;
+            buf = event.buffer;
+            match count {
+                Ok(0) => { return (buf, Err(Error::from(ErrorKind::WriteZero))); }
+                Ok(count) => { wrote += count as usize; }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => { return (buf, Err(e)); }
+            }
+        }
+        if sync {
+            if let Err(e) = self.fsync(FsyncFlags::FSYNC_DATASYNC).await {
+                return (buf, Err(e));
+            }
+        }
+        (buf, Ok(()))
+    }
+
+    async fn fsync(&self, flags: FsyncFlags) -> Result<()> {
+        let event = Fsync { fd: self.fd(), flags };
+        Submission::new(event, self.driver.clone()).await.1?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::paged::WriteBuffer;
+    use futures_lite::future::block_on;
+    use super::IO;
+
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn temp(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("io-backplane-{}-{}", name, std::process::id()))
+    }
+
+    fn staged(len: usize) -> WriteBuffer {
+        let mut write = WriteBuffer::new();
+        let bytes: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        assert_eq!(write.buffer(&bytes).unwrap(), len);
+        write
     }
 
-    pub async fn open_file(&mut self, path: impl AsRef<Path>) -> Result<File> {
-        Ok(File(fs::File::open_on_driver(path, self.driver.clone()).await?))
+    #[test]
+    fn linked_write_and_sync() {
+        let io = match IO::probe() { Some(io) => io, None => return };
+        let path = temp("write-sync");
+        let write = staged(10_000);
+        block_on(async {
+            let file = io.create_file(&path).await.unwrap();
+            let (_, res) = file.write_all_pages(write.buffer, write.low, write.high, 0, true).await;
+            res.unwrap();
+        });
+        let data = fs::read(&path).unwrap();
+        assert_eq!(data.len(), 10_000);
+        assert!(data.iter().enumerate().all(|(i, b)| *b == i as u8));
+        fs::remove_file(&path).unwrap();
     }
 
-    pub async fn from_file(&mut self, file: std::fs::File) -> File {
-        File(fs::File::run_on_driver(file, self.driver.clone()))
+    // A write the descriptor doesn't allow fails, which cancels the linked
+    // fsync; the step-by-step retry then gets the write's own error.
+    #[test]
+    fn linked_write_fails() {
+        let io = match IO::probe() { Some(io) => io, None => return };
+        let path = temp("write-fails");
+        fs::write(&path, b"").unwrap();
+        let write = staged(10_000);
+        let res = block_on(async {
+            let file = io.open_file(&path, fs::OpenOptions::new().read(true)).await.unwrap();
+            file.write_all_pages(write.buffer, write.low, write.high, 0, true).await.1
+        });
+        assert_eq!(res.unwrap_err().raw_os_error(), Some(libc::EBADF));
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+        fs::remove_file(&path).unwrap();
     }
 }
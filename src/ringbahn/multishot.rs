@@ -0,0 +1,553 @@
+// A small ring of our own, for what ringbahn's Submission can't express:
+// it hands back one result and no CQE flags, and a cancelled one drops
+// whatever the kernel produced. Multishot ACCEPT and RECV post a
+// completion per connection or per read, RECV picks its pages from a
+// provided-buffer ring and names the one it used in the flags, and an
+// accept that lands after nobody is waiting for it has to have its
+// descriptor closed. A thread reaps the completions and hands each to the
+// operation its user_data points at.
+//
+// Only Incoming and RecvStream use it. Everything else, single-shot
+// accepts included, goes through the IO's own driver, and without this
+// ring those two do as well.
+
+use crate::buffer::{page_size, Buffer, PageBuf};
+use once_cell::sync::Lazy;
+use super::sys::{self, Buf, BufReg, Cqe, Params, RawSqe};
+
+use std::cmp::max;
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::mem::{size_of, take, zeroed};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::ptr;
+use std::sync::atomic::{AtomicU16, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+const SQ_ENTRIES: u32 = 64;
+// Multishot operations can post faster than they're reaped. The kernel
+// holds on to any overflow, but ends a multishot operation to do it.
+const CQ_ENTRIES: u32 = 4096;
+// Pages in the provided-buffer ring every recv stream shares. A power of
+// two, as the kernel wants.
+const RECV_PAGES: u16 = 64;
+const RECV_GROUP: u16 = 0;
+// user_data that isn't an Op: a cancel's, and a probe's in try_multishot.
+const NO_OP: u64 = 0;
+const PROBE: u64 = 1;
+
+static RING: Lazy<Option<&'static Ring>> = Lazy::new(|| {
+    let ring: &'static Ring = Box::leak(Box::new(Ring::setup().ok()?));
+    thread::Builder::new().name("io-backplane-reaper".into()).spawn(move || ring.reap()).ok()?;
+    Some(ring)
+});
+
+// None if the kernel won't set up another ring, in which case there's no
+// multishot.
+pub(crate) fn ring() -> Option<&'static Ring> {
+    *RING
+}
+
+pub(crate) enum Completed {
+    Accepted(fs::File),
+    // A page from the buffer ring, and how much of it was filled.
+    Received(Buffer, usize),
+    // The peer shut down its side.
+    Closed,
+}
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Accept,
+    Recv,
+}
+
+struct State {
+    kind: Kind,
+    ready: VecDeque<Result<Completed>>,
+    // The kernel has posted the last completion and let go of its Arc.
+    done: bool,
+    // Nobody is left to take completions, so they're dropped as they
+    // come, closing descriptors and returning pages.
+    orphaned: bool,
+    waker: Option<Waker>,
+}
+
+// One operation in flight, which may complete any number of times.
+pub(crate) struct Op {
+    ring: &'static Ring,
+    state: Arc<Mutex<State>>,
+}
+
+// A mapping of part of the ring, or of the buffer ring.
+struct Map(*mut u8, usize);
+
+impl Map {
+    fn ring(fd: RawFd, offset: i64, len: usize) -> Result<Map> {
+        let flags = libc::MAP_SHARED | libc::MAP_POPULATE;
+        let ptr = unsafe { libc::mmap(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE, flags, fd, offset) };
+        if ptr == libc::MAP_FAILED { Err(Error::last_os_error()) } else { Ok(Map(ptr.cast(), len)) }
+    }
+
+    fn anon(len: usize) -> Result<Map> {
+        let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+        let ptr = unsafe { libc::mmap(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE, flags, -1, 0) };
+        if ptr == libc::MAP_FAILED { Err(Error::last_os_error()) } else { Ok(Map(ptr.cast(), len)) }
+    }
+
+    unsafe fn at<T>(&self, offset: u32) -> *mut T {
+        self.0.add(offset as usize).cast()
+    }
+}
+
+impl Drop for Map {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.0.cast(), self.1); }
+    }
+}
+
+struct Sq {
+    head: *const AtomicU32,
+    tail: *const AtomicU32,
+    mask: u32,
+    entries: u32,
+    array: *mut u32,
+    sqes: *mut RawSqe,
+}
+
+struct Cq {
+    head: *const AtomicU32,
+    tail: *const AtomicU32,
+    mask: u32,
+    cqes: *const Cqe,
+}
+
+// The pages the kernel picks from for multishot RECV, by buffer id. Only
+// the reaper takes and refills them once they're registered.
+struct BufRing {
+    map: Map,
+    mask: u16,
+    tail: u16,
+    pages: Vec<Option<Buffer>>,
+    // Ids whose page went out and couldn't be replaced yet.
+    empty: Vec<u16>,
+}
+
+pub(crate) struct Ring {
+    sq: Mutex<Sq>,
+    cq: Cq,
+    // None before 5.19, which can't register one.
+    bufs: Option<Mutex<BufRing>>,
+    multishot_accept: bool,
+    multishot_recv: bool,
+    _maps: Vec<Map>,
+    // Last, so it's closed after the maps go.
+    ring: sys::Ring,
+}
+
+// The raw pointers are into our own mappings. The SQ is only touched
+// under its lock, and the CQ only by the reaper.
+unsafe impl Send for Ring {}
+unsafe impl Sync for Ring {}
+unsafe impl Send for BufRing {}
+
+impl Ring {
+    fn setup() -> Result<Ring> {
+        let mut params: Params = unsafe { zeroed() };
+        params.flags = sys::IORING_SETUP_CQSIZE;
+        params.cq_entries = CQ_ENTRIES;
+        let ring = sys::Ring::setup_with(SQ_ENTRIES, &mut params)?;
+        let (sq_off, cq_off) = (params.sq_off, params.cq_off);
+        let sq_len = sq_off[6] as usize + params.sq_entries as usize * size_of::<u32>();
+        let cq_len = cq_off[5] as usize + params.cq_entries as usize * size_of::<Cqe>();
+        let mut maps = Vec::new();
+        if params.features & sys::IORING_FEAT_SINGLE_MMAP != 0 {
+            maps.push(Map::ring(ring.fd(), sys::IORING_OFF_SQ_RING, max(sq_len, cq_len))?);
+        } else {
+            maps.push(Map::ring(ring.fd(), sys::IORING_OFF_SQ_RING, sq_len)?);
+            maps.push(Map::ring(ring.fd(), sys::IORING_OFF_CQ_RING, cq_len)?);
+        }
+        let sqes = Map::ring(ring.fd(), sys::IORING_OFF_SQES, params.sq_entries as usize * size_of::<RawSqe>())?;
+        let (sq, cq) = unsafe {
+            let (sq_map, cq_map) = (&maps[0], maps.last().unwrap());
+            let sq = Sq {
+                head: sq_map.at(sq_off[0]),
+                tail: sq_map.at(sq_off[1]),
+                mask: *sq_map.at::<u32>(sq_off[2]),
+                entries: *sq_map.at::<u32>(sq_off[3]),
+                array: sq_map.at(sq_off[6]),
+                sqes: sqes.at(0),
+            };
+            let cq = Cq {
+                head: cq_map.at(cq_off[0]),
+                tail: cq_map.at(cq_off[1]),
+                mask: *cq_map.at::<u32>(cq_off[2]),
+                cqes: cq_map.at(cq_off[5]),
+            };
+            (sq, cq)
+        };
+        maps.push(sqes);
+        let bufs = BufRing::register(&ring).ok().map(Mutex::new);
+        let mut ring = Ring {
+            sq: Mutex::new(sq),
+            cq,
+            multishot_accept: false,
+            multishot_recv: false,
+            bufs,
+            _maps: maps,
+            ring,
+        };
+        if let Ok(listener) = idle_listener() {
+            let fd = listener.as_raw_fd();
+            ring.multishot_accept = ring.try_multishot(|sqe| unsafe { prep_accept_multishot(sqe, fd) });
+        }
+        if ring.bufs.is_some() {
+            if let Ok((socket, _peer)) = UnixStream::pair() {
+                let fd = socket.as_raw_fd();
+                ring.multishot_recv = ring.try_multishot(|sqe| unsafe { prep_recv_multishot(sqe, fd) });
+            }
+        }
+        Ok(ring)
+    }
+
+    // Whether the kernel takes a multishot op, found by submitting one on
+    // a socket where nothing will happen: a kernel that doesn't know the
+    // flag fails it with EINVAL straight away, while one that does waits
+    // until we cancel it. We don't go by kernel version or by which other
+    // opcodes the probe lists, since backports break either. Runs before
+    // the reaper starts, so the completions are ours to read.
+    fn try_multishot(&self, prep: impl FnOnce(*mut RawSqe)) -> bool {
+        let pushed = self.push(|sqe| {
+            prep(sqe);
+            unsafe { (*sqe).user_data = PROBE; }
+        });
+        if pushed.is_err() || self.cancel(PROBE).is_err() { return false; }
+        loop {
+            match self.next_cqe() {
+                Ok(cqe) if cqe.user_data == PROBE => { return cqe.res != -libc::EINVAL; }
+                Ok(_) => {}
+                Err(_) => { return false; }
+            }
+        }
+    }
+
+    pub(crate) fn can_accept_multishot(&self) -> bool {
+        self.multishot_accept
+    }
+
+    // Accepts connections until cancelled or the kernel ends it, without
+    // their addresses. Only if can_accept_multishot.
+    pub(crate) fn accept_multishot(&'static self, fd: RawFd) -> Result<Op> {
+        self.submit(Kind::Accept, |sqe| unsafe { prep_accept_multishot(sqe, fd) })
+    }
+
+    pub(crate) fn can_recv_multishot(&self) -> bool {
+        self.multishot_recv
+    }
+
+    // Receives into pages from the buffer ring until cancelled or the
+    // kernel ends it. Only if can_recv_multishot.
+    pub(crate) fn recv_multishot(&'static self, fd: RawFd) -> Result<Op> {
+        self.submit(Kind::Recv, |sqe| unsafe { prep_recv_multishot(sqe, fd) })
+    }
+
+    // The kernel holds a reference to the state, as the user_data, until
+    // the operation's last completion.
+    fn submit(&'static self, kind: Kind, prep: impl FnOnce(*mut RawSqe)) -> Result<Op> {
+        let state = Arc::new(Mutex::new(State {
+            kind,
+            ready: VecDeque::new(),
+            done: false,
+            orphaned: false,
+            waker: None,
+        }));
+        let user_data = Arc::into_raw(state.clone()) as u64;
+        let op = Op { ring: self, state };
+        let mut state = op.state.lock().unwrap();
+        let pushed = self.push(|sqe| {
+            prep(sqe);
+            unsafe { (*sqe).user_data = user_data; }
+        });
+        if pushed.is_err() {
+            // It never went in, so the kernel's reference is ours to drop.
+            state.done = true;
+            drop(unsafe { Arc::from_raw(user_data as *const Mutex<State>) });
+        }
+        drop(state);
+        pushed.map(|()| op)
+    }
+
+    fn cancel(&self, user_data: u64) -> Result<()> {
+        self.push(|sqe| unsafe {
+            sys::prep_rw(sqe, sys::IORING_OP_ASYNC_CANCEL, -1, user_data, 0, 0, 0);
+            (*sqe).user_data = NO_OP;
+        })
+    }
+
+    // Queues one SQE and submits whatever is queued. Only fails if the SQ
+    // is full, before anything is queued: once it's in, an enter that
+    // fails (EAGAIN, EBUSY) leaves it for the next one to submit.
+    fn push(&self, prep: impl FnOnce(*mut RawSqe)) -> Result<()> {
+        let sq = self.sq.lock().unwrap();
+        let pending = unsafe {
+            let tail = (*sq.tail).load(Ordering::Relaxed);
+            let pending = tail.wrapping_sub((*sq.head).load(Ordering::Acquire));
+            if pending == sq.entries {
+                return Err(Error::from_raw_os_error(libc::EBUSY));
+            }
+            let index = tail & sq.mask;
+            let sqe = sq.sqes.add(index as usize);
+            ptr::write_bytes(sqe, 0, 1);
+            prep(sqe);
+            *sq.array.add(index as usize) = index;
+            (*sq.tail).store(tail.wrapping_add(1), Ordering::Release);
+            pending + 1
+        };
+        while let Err(e) = self.ring.enter(pending, 0, 0) {
+            if e.kind() != ErrorKind::Interrupted { break; }
+        }
+        Ok(())
+    }
+
+    fn reap(&self) {
+        while let Ok(cqe) = self.next_cqe() {
+            self.complete(cqe);
+        }
+    }
+
+    // Waits for a completion if there isn't one already.
+    fn next_cqe(&self) -> Result<Cqe> {
+        loop {
+            unsafe {
+                let head = (*self.cq.head).load(Ordering::Relaxed);
+                if head != (*self.cq.tail).load(Ordering::Acquire) {
+                    let cqe = *self.cq.cqes.add((head & self.cq.mask) as usize);
+                    (*self.cq.head).store(head.wrapping_add(1), Ordering::Release);
+                    return Ok(cqe);
+                }
+            }
+            if let Err(e) = self.ring.enter(0, 1, sys::IORING_ENTER_GETEVENTS) {
+                match e.raw_os_error() {
+                    Some(libc::EINTR) | Some(libc::EAGAIN) | Some(libc::EBUSY) => {}
+                    _ => { return Err(e); }
+                }
+            }
+        }
+    }
+
+    fn complete(&self, cqe: Cqe) {
+        if cqe.user_data == NO_OP || cqe.user_data == PROBE { return; }
+        let ptr = cqe.user_data as *const Mutex<State>;
+        let more = cqe.flags & sys::IORING_CQE_F_MORE != 0;
+        // Still alive: the kernel's reference is only dropped below.
+        let mut state = unsafe { &*ptr }.lock().unwrap();
+        let page = if cqe.flags & sys::IORING_CQE_F_BUFFER != 0 {
+            let bid = (cqe.flags >> sys::IORING_CQE_BUFFER_SHIFT) as u16;
+            self.bufs.as_ref().and_then(|bufs| bufs.lock().unwrap().take(bid))
+        } else {
+            None
+        };
+        let completed = if cqe.res < 0 {
+            Err(Error::from_raw_os_error(-cqe.res))
+        } else {
+            match state.kind {
+                Kind::Accept => Ok(Completed::Accepted(unsafe { fs::File::from_raw_fd(cqe.res) })),
+                Kind::Recv => match page {
+                    Some(page) if cqe.res > 0 => Ok(Completed::Received(page, cqe.res as usize)),
+                    _ => Ok(Completed::Closed),
+                },
+            }
+        };
+        let waker = if state.orphaned {
+            drop(completed);
+            None
+        } else {
+            state.ready.push_back(completed);
+            state.waker.take()
+        };
+        if !more { state.done = true; }
+        drop(state);
+        if !more { drop(unsafe { Arc::from_raw(ptr) }); }
+        if let Some(waker) = waker { waker.wake(); }
+    }
+}
+
+unsafe fn prep_accept_multishot(sqe: *mut RawSqe, fd: RawFd) {
+    sys::prep_rw(sqe, sys::IORING_OP_ACCEPT, fd, 0, 0, 0, libc::SOCK_CLOEXEC as u32);
+    (*sqe).ioprio = sys::IORING_ACCEPT_MULTISHOT;
+}
+
+unsafe fn prep_recv_multishot(sqe: *mut RawSqe, fd: RawFd) {
+    sys::prep_rw(sqe, sys::IORING_OP_RECV, fd, 0, 0, 0, 0);
+    (*sqe).ioprio = sys::IORING_RECV_MULTISHOT;
+    (*sqe).flags = sys::IOSQE_BUFFER_SELECT;
+    (*sqe).buf_index = RECV_GROUP;
+}
+
+// A unix socket listening on an address the kernel picks, which nobody
+// will connect to.
+fn idle_listener() -> Result<fs::File> {
+    let fd = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd == -1 { return Err(Error::last_os_error()); }
+    let listener = unsafe { fs::File::from_raw_fd(fd) };
+    // Just the family, so it's bound to an abstract address of its own.
+    let addr: libc::sockaddr_un = unsafe { zeroed() };
+    let len = size_of::<libc::sa_family_t>() as libc::socklen_t;
+    let addr = libc::sockaddr_un { sun_family: libc::AF_UNIX as libc::sa_family_t, ..addr };
+    if unsafe { libc::bind(fd, (&addr as *const libc::sockaddr_un).cast(), len) } == -1 {
+        return Err(Error::last_os_error());
+    }
+    if unsafe { libc::listen(fd, 1) } == -1 { return Err(Error::last_os_error()); }
+    Ok(listener)
+}
+
+impl BufRing {
+    fn register(ring: &sys::Ring) -> Result<BufRing> {
+        let map = Map::anon(RECV_PAGES as usize * size_of::<Buf>())?;
+        let mut reg = BufReg {
+            ring_addr: map.0 as u64,
+            ring_entries: RECV_PAGES as u32,
+            bgid: RECV_GROUP,
+            flags: 0,
+            resv: [0; 3],
+        };
+        ring.register(sys::IORING_REGISTER_PBUF_RING, (&mut reg as *mut BufReg).cast(), 1)?;
+        let mut bufs = BufRing {
+            map,
+            mask: RECV_PAGES - 1,
+            tail: 0,
+            pages: (0..RECV_PAGES).map(|_| None).collect(),
+            empty: (0..RECV_PAGES).rev().collect(),
+        };
+        bufs.refill();
+        Ok(bufs)
+    }
+
+    // The page the kernel filled, replacing it in the ring.
+    fn take(&mut self, bid: u16) -> Option<Buffer> {
+        let page = self.pages.get_mut(bid as usize)?.take()?;
+        self.empty.push(bid);
+        self.refill();
+        Some(page)
+    }
+
+    // As many of the missing pages as the pool will give us.
+    fn refill(&mut self) {
+        let old_tail = self.tail;
+        while let Some(bid) = self.empty.pop() {
+            let mut page = match Buffer::with_pages(1) {
+                Ok(page) => PageBuf(page),
+                Err(_) => { self.empty.push(bid); break; }
+            };
+            unsafe {
+                let buf = &mut *self.map.at::<Buf>(0).add((self.tail & self.mask) as usize);
+                buf.addr = page.as_mut().as_mut_ptr() as u64;
+                buf.len = page_size() as u32;
+                buf.bid = bid;
+            }
+            self.pages[bid as usize] = Some(page.0);
+            self.tail = self.tail.wrapping_add(1);
+        }
+        if self.tail != old_tail {
+            let tail = unsafe { &*ptr::addr_of_mut!((*self.map.at::<Buf>(0)).resv).cast::<AtomicU16>() };
+            tail.store(self.tail, Ordering::Release);
+        }
+    }
+}
+
+impl Op {
+    // None once the kernel has ended the operation and everything it
+    // produced has been taken.
+    pub(crate) fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<Completed>>> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(next) = state.ready.pop_front() { return Poll::Ready(Some(next)); }
+        if state.done { return Poll::Ready(None); }
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for Op {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.orphaned = true;
+        state.waker = None;
+        let ready = take(&mut state.ready);
+        let done = state.done;
+        drop(state);
+        drop(ready);
+        if !done {
+            // If this fails the operation runs until the socket closes,
+            // and whatever it produces is dropped on arrival.
+            let _ = self.ring.cancel(Arc::as_ptr(&self.state) as u64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_lite::future::block_on;
+    use super::{ring, Completed, Op, Ring};
+
+    use std::fs;
+    use std::future::poll_fn;
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, TcpListener, TcpStream};
+    use std::os::unix::io::AsRawFd;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    fn next(op: &mut Op) -> Option<std::io::Result<Completed>> {
+        block_on(poll_fn(|cx| op.poll_next(cx)))
+    }
+
+    // Rearms the way Incoming does when the kernel ends the accept.
+    fn accepted(ring: &'static Ring, listener: &TcpListener, op: &mut Op) -> fs::File {
+        loop {
+            match next(op) {
+                Some(Ok(Completed::Accepted(file))) => { return file; }
+                None => { *op = ring.accept_multishot(listener.as_raw_fd()).unwrap(); }
+                _ => panic!("expected a connection"),
+            }
+        }
+    }
+
+    #[test]
+    fn accepts_and_receives() {
+        let ring = match ring() { Some(ring) if ring.can_accept_multishot() => ring, _ => return };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut accept = ring.accept_multishot(listener.as_raw_fd()).unwrap();
+        for _ in 0..3 {
+            let mut client = TcpStream::connect(addr).unwrap();
+            let server = accepted(ring, &listener, &mut accept);
+            if !ring.can_recv_multishot() { continue; }
+            let mut recv = ring.recv_multishot(server.as_raw_fd()).unwrap();
+            client.write_all(b"hello").unwrap();
+            match next(&mut recv) {
+                Some(Ok(Completed::Received(page, 5))) => assert_eq!(page.read_first(0, 5).unwrap(), b"hello"),
+                _ => panic!("expected five bytes"),
+            }
+            client.shutdown(Shutdown::Write).unwrap();
+            assert!(matches!(next(&mut recv), Some(Ok(Completed::Closed))));
+        }
+    }
+
+    // A connection accepted after the Op is dropped is closed, so the
+    // client sees end of file rather than waiting.
+    #[test]
+    fn dropped_accept_closes() {
+        let ring = match ring() { Some(ring) if ring.can_accept_multishot() => ring, _ => return };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let accept = ring.accept_multishot(listener.as_raw_fd()).unwrap();
+        sleep(Duration::from_millis(100));
+        drop(accept);
+        assert_eq!(client.read(&mut [0; 8]).unwrap(), 0);
+    }
+}
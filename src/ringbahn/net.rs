@@ -0,0 +1,446 @@
+use crate::buffer::{page_size, Buffer, PageBuf};
+use crate::io::OwnedBuf;
+use crate::net::{Datagram, Datagrams, SockAddr};
+use futures_core::Stream;
+use maglev::Driver;
+use ringbahn::Submission;
+use super::event::{Accept, Connect, Fd, MsgParts, Recv, RecvMsg, RecvPages, Send, SendMsg, SendPages};
+use super::multishot::{self, Completed};
+use super::{sys, IO};
+
+use std::future::Future;
+use std::io::{Error, Result};
+use std::mem::replace;
+use std::net::{self, Shutdown, SocketAddr};
+use std::fs;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+const NET_OPS: &[u8] = &[
+    sys::IORING_OP_ACCEPT,
+    sys::IORING_OP_CONNECT,
+    sys::IORING_OP_SEND,
+    sys::IORING_OP_RECV,
+    sys::IORING_OP_SENDMSG,
+    sys::IORING_OP_RECVMSG,
+];
+
+pub struct TcpListener {
+    socket: net::TcpListener,
+    driver: Driver,
+}
+
+pub struct TcpStream {
+    socket: net::TcpStream,
+    driver: Driver,
+}
+
+// Streams over multishot ACCEPT and RECV on the ring in multishot.rs,
+// rearmed whenever the kernel ends one. Where the kernel lacks them, or
+// that ring couldn't be set up, they go one at a time through the driver.
+pub struct Incoming<'a> {
+    listener: &'a TcpListener,
+    accept: Option<Accepting<'a>>,
+}
+
+enum Accepting<'a> {
+    Multishot(multishot::Op),
+    Single(Op<'a, Result<(TcpStream, SocketAddr)>>),
+}
+
+pub struct RecvStream<'a> {
+    stream: &'a TcpStream,
+    recv: Option<Receiving<'a>>,
+}
+
+enum Receiving<'a> {
+    Multishot(multishot::Op),
+    Single(Op<'a, (Buffer, Result<usize>)>),
+}
+
+type Op<'a, T> = Pin<Box<dyn Future<Output = T> + 'a>>;
+
+pub struct UdpSocket {
+    socket: net::UdpSocket,
+    driver: Driver,
+}
+
+// Polls a set of futures together until they're all done, so their SQEs
+// reach the kernel in the same submission.
+struct JoinAll<F: Future> {
+    futures: Vec<Option<Pin<Box<F>>>>,
+    outputs: Vec<Option<F::Output>>,
+}
+
+fn join_all<F: Future>(futures: impl IntoIterator<Item = F>) -> JoinAll<F> {
+    let futures: Vec<_> = futures.into_iter().map(|f| Some(Box::pin(f))).collect();
+    let outputs = futures.iter().map(|_| None).collect();
+    JoinAll { futures, outputs }
+}
+
+// The futures are boxed and the outputs are never pinned.
+impl<F: Future> Unpin for JoinAll<F> {}
+
+impl<F: Future> Future for JoinAll<F> {
+    type Output = Vec<F::Output>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Vec<F::Output>> {
+        let this = self.get_mut();
+        let mut pending = false;
+        for (future, output) in this.futures.iter_mut().zip(this.outputs.iter_mut()) {
+            if let Some(f) = future {
+                match f.as_mut().poll(cx) {
+                    Poll::Ready(out) => {
+                        *output = Some(out);
+                        *future = None;
+                    }
+                    Poll::Pending => { pending = true; }
+                }
+            }
+        }
+        if pending {
+            Poll::Pending
+        } else {
+            Poll::Ready(this.outputs.iter_mut().map(|out| out.take().unwrap()).collect())
+        }
+    }
+}
+
+impl IO {
+    // Whether the kernel has everything the sockets need; if not, the
+    // unified layer hands out threadpool sockets instead.
+    pub fn supports_net(&self) -> bool {
+        NET_OPS.iter().all(|op| self.probe.supports(*op))
+    }
+
+    // Binding and listening don't block, so they're done directly.
+    pub async fn bind_tcp(&self, addr: SocketAddr) -> Result<TcpListener> {
+        let socket = net::TcpListener::bind(addr)?;
+        Ok(TcpListener { socket, driver: self.driver.clone() })
+    }
+
+    pub async fn bind_udp(&self, addr: SocketAddr) -> Result<UdpSocket> {
+        let socket = net::UdpSocket::bind(addr)?;
+        Ok(UdpSocket { socket, driver: self.driver.clone() })
+    }
+
+    pub async fn connect_tcp(&self, addr: SocketAddr) -> Result<TcpStream> {
+        let domain = if addr.is_ipv4() { libc::AF_INET } else { libc::AF_INET6 };
+        let fd = unsafe { libc::socket(domain, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+        if fd == -1 {
+            return Err(Error::last_os_error());
+        }
+        // Owned from here so it's closed if the connect fails.
+        let socket = unsafe { net::TcpStream::from_raw_fd(fd) };
+        let event = Connect { fd: Fd::Raw(fd), addr: Box::new(SockAddr::from_addr(&addr)) };
+        Submission::new(event, self.driver.clone()).await.1?;
+        Ok(TcpStream { socket, driver: self.driver.clone() })
+    }
+}
+
+impl AsRawFd for TcpListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+impl TcpListener {
+    pub async fn accept(&self) -> Result<(TcpStream, SocketAddr)> {
+        let event = Accept { fd: Fd::Raw(self.socket.as_raw_fd()), addr: Box::new(SockAddr::empty()) };
+        let (event, fd) = Submission::new(event, self.driver.clone()).await;
+        let socket = unsafe { net::TcpStream::from_raw_fd(fd? as RawFd) };
+        Ok((TcpStream { socket, driver: self.driver.clone() }, event.addr.to_addr()?))
+    }
+
+    // Multishot accepts don't say who connected, so we ask the socket.
+    fn adopt(&self, file: fs::File) -> Result<(TcpStream, SocketAddr)> {
+        let socket = unsafe { net::TcpStream::from_raw_fd(file.into_raw_fd()) };
+        let addr = socket.peer_addr()?;
+        Ok((TcpStream { socket, driver: self.driver.clone() }, addr))
+    }
+
+    fn start_accept(&self) -> Result<Accepting<'_>> {
+        match multishot::ring() {
+            Some(ring) if ring.can_accept_multishot() => {
+                Ok(Accepting::Multishot(ring.accept_multishot(self.socket.as_raw_fd())?))
+            }
+            _ => Ok(Accepting::Single(Box::pin(self.accept()))),
+        }
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    // Every connection as it's accepted. Never ends.
+    pub fn incoming(&self) -> Incoming<'_> {
+        Incoming { listener: self, accept: None }
+    }
+}
+
+impl<'a> Stream for Incoming<'a> {
+    type Item = Result<(TcpStream, SocketAddr)>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let listener = self.listener;
+        loop {
+            let accept = match &mut self.accept {
+                Some(accept) => accept,
+                None => match listener.start_accept() {
+                    Ok(accept) => self.accept.insert(accept),
+                    Err(e) => { return Poll::Ready(Some(Err(e))); }
+                },
+            };
+            match accept {
+                Accepting::Multishot(op) => match op.poll_next(cx) {
+                    Poll::Ready(Some(Ok(Completed::Accepted(file)))) => {
+                        return Poll::Ready(Some(listener.adopt(file)));
+                    }
+                    Poll::Ready(Some(Ok(_))) => {}
+                    Poll::Ready(Some(Err(e))) => { return Poll::Ready(Some(Err(e))); }
+                    // Ended by the kernel; go again.
+                    Poll::Ready(None) => { self.accept = None; }
+                    Poll::Pending => { return Poll::Pending; }
+                },
+                Accepting::Single(accept) => {
+                    let accepted = match accept.as_mut().poll(cx) {
+                        Poll::Ready(accepted) => accepted,
+                        Poll::Pending => { return Poll::Pending; }
+                    };
+                    self.accept = None;
+                    return Poll::Ready(Some(accepted));
+                }
+            }
+        }
+    }
+}
+
+impl AsRawFd for TcpStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+impl TcpStream {
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn peer_addr(&self) -> Result<SocketAddr> {
+        self.socket.peer_addr()
+    }
+
+    pub fn set_nodelay(&self, nodelay: bool) -> Result<()> {
+        self.socket.set_nodelay(nodelay)
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> Result<()> {
+        self.socket.shutdown(how)
+    }
+
+    fn fd(&self) -> Fd {
+        Fd::Raw(self.socket.as_raw_fd())
+    }
+
+    pub async fn recv<B: OwnedBuf>(&self, buf: B) -> (B, Result<usize>) {
+        let event = Recv { fd: self.fd(), buf };
+        let (event, read) = Submission::new(event, self.driver.clone()).await;
+        (event.buf, read.map(|read| read as usize))
+    }
+
+    pub async fn send<B: OwnedBuf>(&self, buf: B) -> (B, Result<usize>) {
+        let event = Send { fd: self.fd(), buf };
+        let (event, wrote) = Submission::new(event, self.driver.clone()).await;
+        (event.buf, wrote.map(|wrote| wrote as usize))
+    }
+
+    // Whatever arrives, a page at a time from the buffer pool, until the
+    // peer shuts down its side.
+    pub fn recv_stream(&self) -> RecvStream<'_> {
+        RecvStream { stream: self, recv: None }
+    }
+
+    fn start_recv(&self) -> Result<Receiving<'_>> {
+        match multishot::ring() {
+            Some(ring) if ring.can_recv_multishot() => {
+                Ok(Receiving::Multishot(ring.recv_multishot(self.socket.as_raw_fd())?))
+            }
+            _ => Ok(Receiving::Single(Box::pin(self.recv_pages(Buffer::with_pages(1)?, 0)))),
+        }
+    }
+
+    pub(crate) async fn recv_pages(&self, buf: Buffer, high: usize) -> (Buffer, Result<usize>) {
+        let event = match RecvPages::new(self.fd(), buf, high) {
+            Ok(event) => event,
+            Err((buf, e)) => { return (buf, Err(e)); }
+        };
+        let (event, read) = Submission::new(event, self.driver.clone()).await;
+        (event.buffer, read.map(|read| read as usize))
+    }
+
+    pub(crate) async fn send_pages(&self, buf: Buffer, low: usize, high: usize) -> (Buffer, Result<usize>) {
+        let event = SendPages::new(self.fd(), buf, low, high);
+        if event.is_empty() { return (event.buffer, Ok(0)); }
+        let (event, wrote) = Submission::new(event, self.driver.clone()).await;
+        (event.buffer, wrote.map(|wrote| wrote as usize))
+    }
+}
+
+impl<'a> Stream for RecvStream<'a> {
+    // A page and how much of it was filled.
+    type Item = Result<(Buffer, usize)>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let stream = self.stream;
+        loop {
+            let recv = match &mut self.recv {
+                Some(recv) => recv,
+                None => match stream.start_recv() {
+                    Ok(recv) => self.recv.insert(recv),
+                    Err(e) => { return Poll::Ready(Some(Err(e))); }
+                },
+            };
+            match recv {
+                Receiving::Multishot(op) => match op.poll_next(cx) {
+                    Poll::Ready(Some(Ok(Completed::Received(page, read)))) => {
+                        return Poll::Ready(Some(Ok((page, read))));
+                    }
+                    Poll::Ready(Some(Ok(Completed::Closed))) => { return Poll::Ready(None); }
+                    Poll::Ready(Some(Ok(_))) => {}
+                    // ENOBUFS means the pool couldn't keep the buffer ring
+                    // stocked; polling again rearms.
+                    Poll::Ready(Some(Err(e))) => { return Poll::Ready(Some(Err(e))); }
+                    Poll::Ready(None) => { self.recv = None; }
+                    Poll::Pending => { return Poll::Pending; }
+                },
+                Receiving::Single(recv) => {
+                    let (page, read) = match recv.as_mut().poll(cx) {
+                        Poll::Ready(ret) => ret,
+                        Poll::Pending => { return Poll::Pending; }
+                    };
+                    self.recv = None;
+                    return match read {
+                        Ok(0) => Poll::Ready(None),
+                        Ok(read) => Poll::Ready(Some(Ok((page, read)))),
+                        Err(e) => Poll::Ready(Some(Err(e))),
+                    };
+                }
+            }
+        }
+    }
+}
+
+impl AsRawFd for UdpSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+impl UdpSocket {
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    fn fd(&self) -> Fd {
+        Fd::Raw(self.socket.as_raw_fd())
+    }
+
+    pub async fn send_to<B: OwnedBuf>(&self, buf: B, addr: SocketAddr) -> (B, Result<usize>) {
+        let len = buf.as_ref().len();
+        let event = SendMsg { fd: self.fd(), buf, len, parts: MsgParts::new(SockAddr::from_addr(&addr)) };
+        let (event, wrote) = Submission::new(event, self.driver.clone()).await;
+        (event.buf, wrote.map(|wrote| wrote as usize))
+    }
+
+    pub async fn recv_from<B: OwnedBuf>(&self, buf: B) -> (B, Result<(usize, SocketAddr)>) {
+        let event = RecvMsg { fd: self.fd(), buf, parts: MsgParts::new(SockAddr::empty()), flags: 0 };
+        let (mut event, read) = Submission::new(event, self.driver.clone()).await;
+        let read = read.and_then(|read| Ok((read as usize, event.parts.received().1.to_addr()?)));
+        (event.buf, read)
+    }
+
+    // One SENDMSG per datagram, all submitted together. Pages past the
+    // last datagram stay in the batch, after the rest.
+    pub(crate) async fn send_batch(&self, batch: Datagrams) -> (Datagrams, Result<usize>) {
+        let Datagrams { buffer, datagrams } = batch;
+        let count = datagrams.len();
+        let mut pages = buffer.into_pages().into_iter();
+        // Datagrams first, so zip doesn't take a page it has no use for.
+        let events = datagrams.iter().zip(pages.by_ref()).map(|(d, page)| {
+            let parts = MsgParts::new(SockAddr::from_addr(&d.addr));
+            let event = SendMsg { fd: self.fd(), buf: PageBuf(page), len: d.len, parts };
+            Submission::new(event, self.driver.clone())
+        });
+        let events = join_all(events);
+        let mut buffer = Buffer::new();
+        let mut sent = 0;
+        let mut error = None;
+        for (event, wrote) in events.await {
+            buffer.append(event.buf.0);
+            match wrote {
+                Ok(_) => { sent += 1; }
+                Err(e) => { error.get_or_insert(e); }
+            }
+        }
+        pages.for_each(|page| buffer.append(page));
+        let batch = Datagrams { buffer, datagrams };
+        match error {
+            Some(e) if sent == 0 && count > 0 => (batch, Err(e)),
+            _ => (batch, Ok(sent)),
+        }
+    }
+
+    // Waits on one RECVMSG, then queues up to max - 1 more with
+    // MSG_DONTWAIT to drain whatever else has arrived. The first has to
+    // finish before the rest go in, or a non-blocking one could take the
+    // only datagram and leave it waiting.
+    pub(crate) async fn recv_batch(&self, batch: Datagrams, max: usize) -> (Datagrams, Result<usize>) {
+        let mut batch = batch;
+        batch.clear();
+        if max == 0 { return (batch, Ok(0)); }
+        if let Err(e) = batch.buffer.reserve(max * page_size()) {
+            return (batch, Err(e));
+        }
+        let mut pages = replace(&mut batch.buffer, Buffer::new()).into_pages().into_iter();
+        let first = pages.next().unwrap();
+        let (event, read) = self.recv_page(first, 0).await;
+        batch.buffer.append(event.buf.0);
+        let first = self.received(event.parts, read.map(|read| read as usize));
+        match first {
+            Ok(datagram) => batch.datagrams.push(datagram),
+            Err(e) => {
+                pages.for_each(|page| batch.buffer.append(page));
+                return (batch, Err(e));
+            }
+        }
+        let rest = join_all(pages.by_ref().take(max - 1).map(|page| self.recv_page(page, libc::MSG_DONTWAIT)));
+        let mut spare = Buffer::new();
+        for (event, read) in rest.await {
+            // EAGAIN just means the queue ran dry.
+            match self.received(event.parts, read.map(|read| read as usize)) {
+                Ok(datagram) => {
+                    batch.buffer.append(event.buf.0);
+                    batch.datagrams.push(datagram);
+                }
+                Err(_) => spare.append(event.buf.0),
+            }
+        }
+        // Pages past `max` go back unused, after the ones that were tried.
+        pages.for_each(|page| spare.append(page));
+        batch.buffer.append(spare);
+        let count = batch.len();
+        (batch, Ok(count))
+    }
+
+    fn recv_page(&self, page: Buffer, flags: i32) -> Submission<RecvMsg<PageBuf>, Driver> {
+        let event = RecvMsg { fd: self.fd(), buf: PageBuf(page), parts: MsgParts::new(SockAddr::empty()), flags };
+        Submission::new(event, self.driver.clone())
+    }
+
+    fn received(&self, mut parts: Box<MsgParts>, read: Result<usize>) -> Result<Datagram> {
+        let len = read?;
+        let (truncated, addr) = parts.received();
+        Ok(Datagram { len, addr: addr.to_addr()?, truncated })
+    }
+}
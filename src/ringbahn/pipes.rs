@@ -0,0 +1,61 @@
+use concurrent_queue::ConcurrentQueue;
+use once_cell::sync::Lazy;
+
+use std::fs;
+use std::io::Error;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+// How many idle pipes we keep around. Anything beyond this is closed.
+const POOL_SIZE: usize = 32;
+
+// splice needs a pipe on one side, so a file-to-socket transfer goes
+// through one. Making a pipe costs two descriptors and a syscall, and
+// each one pins a page buffer in the kernel, so we reuse them.
+static PIPES: Lazy<ConcurrentQueue<Pipe>> = Lazy::new(|| ConcurrentQueue::bounded(POOL_SIZE));
+
+pub(crate) struct Pipe {
+    read: fs::File,
+    write: fs::File,
+    capacity: usize,
+}
+
+impl Pipe {
+    pub(crate) fn take() -> Result<Pipe, Error> {
+        match PIPES.pop() {
+            Ok(pipe) => Ok(pipe),
+            Err(_) => Pipe::new(),
+        }
+    }
+
+    fn new() -> Result<Pipe, Error> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } == -1 {
+            return Err(Error::last_os_error());
+        }
+        let (read, write) = unsafe { (fs::File::from_raw_fd(fds[0]), fs::File::from_raw_fd(fds[1])) };
+        let capacity = unsafe { libc::fcntl(fds[1], libc::F_GETPIPE_SZ) };
+        if capacity == -1 {
+            return Err(Error::last_os_error());
+        }
+        Ok(Pipe { read, write, capacity: capacity as usize })
+    }
+
+    // Only for pipes that are known to be empty. One with data left in it
+    // should just be dropped, which closes it.
+    pub(crate) fn give_back(self) {
+        #[allow(unused_must_use)]
+        { PIPES.push(self); }
+    }
+
+    pub(crate) fn read_fd(&self) -> RawFd {
+        self.read.as_raw_fd()
+    }
+
+    pub(crate) fn write_fd(&self) -> RawFd {
+        self.write.as_raw_fd()
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
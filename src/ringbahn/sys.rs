@@ -0,0 +1,260 @@
+// Just enough of the raw io_uring ABI to ask the kernel what it supports
+// without going through a driver, which would panic on a kernel that
+// can't set a ring up, and to run the small ring in multishot.rs.
+
+use libc::{
+    c_long, c_uint, c_void, close, syscall, SYS_io_uring_enter, SYS_io_uring_register, SYS_io_uring_setup
+};
+use once_cell::sync::Lazy;
+use std::io::Error;
+use std::mem::zeroed;
+use std::os::unix::io::RawFd;
+use std::ptr;
+
+pub(crate) const IORING_OP_READV: u8 = 1;
+pub(crate) const IORING_OP_WRITEV: u8 = 2;
+pub(crate) const IORING_OP_FSYNC: u8 = 3;
+pub(crate) const IORING_OP_READ_FIXED: u8 = 4;
+pub(crate) const IORING_OP_WRITE_FIXED: u8 = 5;
+pub(crate) const IORING_OP_SYNC_FILE_RANGE: u8 = 8;
+pub(crate) const IORING_OP_SENDMSG: u8 = 9;
+pub(crate) const IORING_OP_RECVMSG: u8 = 10;
+pub(crate) const IORING_OP_ACCEPT: u8 = 13;
+pub(crate) const IORING_OP_ASYNC_CANCEL: u8 = 14;
+pub(crate) const IORING_OP_CONNECT: u8 = 16;
+pub(crate) const IORING_OP_OPENAT: u8 = 18;
+pub(crate) const IORING_OP_CLOSE: u8 = 19;
+pub(crate) const IORING_OP_STATX: u8 = 21;
+pub(crate) const IORING_OP_READ: u8 = 22;
+pub(crate) const IORING_OP_WRITE: u8 = 23;
+pub(crate) const IORING_OP_SEND: u8 = 26;
+pub(crate) const IORING_OP_RECV: u8 = 27;
+pub(crate) const IORING_OP_SPLICE: u8 = 30;
+pub(crate) const IORING_OP_TEE: u8 = 33;
+pub(crate) const IORING_OP_RENAMEAT: u8 = 35;
+pub(crate) const IORING_OP_UNLINKAT: u8 = 36;
+pub(crate) const IORING_OP_MKDIRAT: u8 = 37;
+pub(crate) const IORING_OP_SYMLINKAT: u8 = 38;
+pub(crate) const IORING_OP_LINKAT: u8 = 39;
+
+pub(crate) const IORING_SETUP_CQSIZE: u32 = 1 << 3;
+pub(crate) const IORING_FEAT_SINGLE_MMAP: u32 = 1;
+pub(crate) const IORING_ENTER_GETEVENTS: u32 = 1;
+pub(crate) const IORING_OFF_SQ_RING: i64 = 0;
+pub(crate) const IORING_OFF_CQ_RING: i64 = 0x8000000;
+pub(crate) const IORING_OFF_SQES: i64 = 0x10000000;
+
+pub(crate) const IOSQE_BUFFER_SELECT: u8 = 1 << 5;
+// In the SQE's ioprio.
+pub(crate) const IORING_RECV_MULTISHOT: u16 = 1 << 1;
+pub(crate) const IORING_ACCEPT_MULTISHOT: u16 = 1;
+
+pub(crate) const IORING_CQE_F_BUFFER: u32 = 1;
+pub(crate) const IORING_CQE_F_MORE: u32 = 1 << 1;
+pub(crate) const IORING_CQE_BUFFER_SHIFT: u32 = 16;
+
+pub(crate) const IORING_REGISTER_BUFFERS: c_uint = 0;
+pub(crate) const IORING_REGISTER_FILES: c_uint = 2;
+pub(crate) const IORING_REGISTER_FILES_UPDATE: c_uint = 6;
+const IORING_REGISTER_PROBE: c_uint = 8;
+pub(crate) const IORING_REGISTER_PBUF_RING: c_uint = 22;
+const IO_URING_OP_SUPPORTED: u16 = 1;
+const PROBE_OPS: usize = 256;
+
+#[repr(C)]
+pub(crate) struct Params {
+    pub(crate) sq_entries: u32,
+    pub(crate) cq_entries: u32,
+    pub(crate) flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    pub(crate) features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    // head, tail, ring_mask, ring_entries, flags, dropped, array, ...
+    pub(crate) sq_off: [u32; 10],
+    // head, tail, ring_mask, ring_entries, overflow, cqes, flags, ...
+    pub(crate) cq_off: [u32; 10],
+}
+
+// The kernel's io_uring_sqe, for opcodes iou has no prep method for and
+// for our own ring.
+#[repr(C)]
+pub(crate) struct RawSqe {
+    opcode: u8,
+    pub(crate) flags: u8,
+    pub(crate) ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    op_flags: u32,
+    pub(crate) user_data: u64,
+    // The buffer group, with IOSQE_BUFFER_SELECT.
+    pub(crate) buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    pad: [u64; 2],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct Cqe {
+    pub(crate) user_data: u64,
+    pub(crate) res: i32,
+    pub(crate) flags: u32,
+}
+
+// One slot of a provided-buffer ring. The ring's tail lives in the first
+// slot's `resv`.
+#[repr(C)]
+pub(crate) struct Buf {
+    pub(crate) addr: u64,
+    pub(crate) len: u32,
+    pub(crate) bid: u16,
+    pub(crate) resv: u16,
+}
+
+#[repr(C)]
+pub(crate) struct BufReg {
+    pub(crate) ring_addr: u64,
+    pub(crate) ring_entries: u32,
+    pub(crate) bgid: u16,
+    pub(crate) flags: u16,
+    pub(crate) resv: [u64; 3],
+}
+
+// What liburing's io_uring_prep_rw does, except that user_data is left
+// for the driver to set.
+pub(crate) unsafe fn prep_rw(sqe: *mut RawSqe, opcode: u8, fd: RawFd, addr: u64, len: u32, off: u64, op_flags: u32) {
+    let sqe = &mut *sqe;
+    sqe.opcode = opcode;
+    sqe.flags = 0;
+    sqe.ioprio = 0;
+    sqe.fd = fd;
+    sqe.off = off;
+    sqe.addr = addr;
+    sqe.len = len;
+    sqe.op_flags = op_flags;
+    sqe.buf_index = 0;
+    sqe.personality = 0;
+    sqe.splice_fd_in = 0;
+    sqe.pad = [0; 2];
+}
+
+#[repr(C)]
+pub(crate) struct FilesUpdate {
+    pub(crate) offset: u32,
+    pub(crate) resv: u32,
+    pub(crate) fds: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ProbeOp {
+    op: u8,
+    resv: u8,
+    flags: u16,
+    resv2: u32,
+}
+
+#[repr(C)]
+struct RawProbe {
+    last_op: u8,
+    ops_len: u8,
+    resv: u16,
+    resv2: [u32; 3],
+    ops: [ProbeOp; PROBE_OPS],
+}
+
+// The opcodes the running kernel claims to support, as a bitset.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Probe {
+    ops: [u64; PROBE_OPS / 64],
+}
+
+impl Probe {
+    pub(crate) fn supports(&self, op: u8) -> bool {
+        self.ops[op as usize / 64] & (1 << (op as usize % 64)) != 0
+    }
+
+    fn set(&mut self, op: u8) {
+        self.ops[op as usize / 64] |= 1 << (op as usize % 64);
+    }
+}
+
+// Probing costs a ring setup and teardown, so we only do it once.
+static PROBE: Lazy<Option<Probe>> = Lazy::new(|| probe().ok());
+
+pub(crate) fn probed() -> Option<Probe> {
+    *PROBE
+}
+
+fn probe() -> Result<Probe, Error> {
+    let ring = Ring::setup(1)?;
+    let mut raw: Box<RawProbe> = Box::new(unsafe { zeroed() });
+    ring.register(IORING_REGISTER_PROBE, (&mut *raw as *mut RawProbe).cast(), PROBE_OPS as c_uint)?;
+    let mut probe = Probe::default();
+    for op in &raw.ops[..raw.ops_len as usize] {
+        if op.flags & IO_URING_OP_SUPPORTED != 0 { probe.set(op.op); }
+    }
+    Ok(probe)
+}
+
+pub(crate) struct Ring(RawFd);
+
+impl Ring {
+    pub(crate) fn setup(entries: u32) -> Result<Ring, Error> {
+        Ring::setup_with(entries, &mut unsafe { zeroed() })
+    }
+
+    // The kernel fills in the rest of `params`, which says how to map the
+    // ring.
+    pub(crate) fn setup_with(entries: u32, params: &mut Params) -> Result<Ring, Error> {
+        let ret = unsafe { syscall(SYS_io_uring_setup, entries as c_long, params as *mut Params) };
+        if ret < 0 {
+            Err(Error::last_os_error())
+        } else {
+            Ok(Ring(ret as RawFd))
+        }
+    }
+
+    pub(crate) fn fd(&self) -> RawFd {
+        self.0
+    }
+
+    pub(crate) fn register(&self, opcode: c_uint, arg: *mut c_void, nr_args: c_uint) -> Result<(), Error> {
+        register(self.0, opcode, arg, nr_args)
+    }
+
+    // Submits `to_submit` SQEs, then with GETEVENTS waits for at least
+    // `min_complete` completions.
+    pub(crate) fn enter(&self, to_submit: u32, min_complete: u32, flags: u32) -> Result<u32, Error> {
+        let ret = unsafe {
+            syscall(
+                SYS_io_uring_enter, self.0 as c_long, to_submit as c_long, min_complete as c_long,
+                flags as c_long, ptr::null::<c_void>(), 0 as c_long
+            )
+        };
+        if ret < 0 {
+            Err(Error::last_os_error())
+        } else {
+            Ok(ret as u32)
+        }
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        unsafe { close(self.0); }
+    }
+}
+
+pub(crate) fn register(ring: RawFd, opcode: c_uint, arg: *mut c_void, nr_args: c_uint) -> Result<(), Error> {
+    let ret = unsafe { syscall(SYS_io_uring_register, ring as c_long, opcode as c_long, arg, nr_args as c_long) };
+    if ret < 0 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
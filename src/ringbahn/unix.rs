@@ -0,0 +1,167 @@
+use crate::io::OwnedBuf;
+use crate::net::SockAddr;
+use crate::unix::Control;
+use maglev::Driver;
+use ringbahn::Submission;
+use super::event::{Accept, Connect, Fd, MsgParts, Recv, RecvMsg, Send, SendMsg};
+use super::IO;
+
+use std::fs;
+use std::io::{Error, Result};
+use std::net::Shutdown;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net;
+use std::path::Path;
+
+pub struct UnixListener {
+    socket: net::UnixListener,
+    driver: Driver,
+}
+
+pub struct UnixStream {
+    socket: net::UnixStream,
+    driver: Driver,
+}
+
+pub struct UnixDatagram {
+    socket: net::UnixDatagram,
+    driver: Driver,
+}
+
+impl IO {
+    pub async fn bind_unix(&self, path: impl AsRef<Path>) -> Result<UnixListener> {
+        let socket = net::UnixListener::bind(path)?;
+        Ok(UnixListener { socket, driver: self.driver.clone() })
+    }
+
+    pub async fn connect_unix(&self, path: impl AsRef<Path>) -> Result<UnixStream> {
+        let addr = SockAddr::from_path(path.as_ref())?;
+        let fd = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+        if fd == -1 {
+            return Err(Error::last_os_error());
+        }
+        let socket = unsafe { net::UnixStream::from_raw_fd(fd) };
+        let event = Connect { fd: Fd::Raw(fd), addr: Box::new(addr) };
+        Submission::new(event, self.driver.clone()).await.1?;
+        Ok(UnixStream { socket, driver: self.driver.clone() })
+    }
+
+    pub fn unix_pair(&self) -> Result<(UnixStream, UnixStream)> {
+        let (a, b) = net::UnixStream::pair()?;
+        Ok((
+            UnixStream { socket: a, driver: self.driver.clone() },
+            UnixStream { socket: b, driver: self.driver.clone() },
+        ))
+    }
+
+    pub async fn bind_unix_datagram(&self, path: impl AsRef<Path>) -> Result<UnixDatagram> {
+        let socket = net::UnixDatagram::bind(path)?;
+        Ok(UnixDatagram { socket, driver: self.driver.clone() })
+    }
+
+    pub fn unix_datagram_pair(&self) -> Result<(UnixDatagram, UnixDatagram)> {
+        let (a, b) = net::UnixDatagram::pair()?;
+        Ok((
+            UnixDatagram { socket: a, driver: self.driver.clone() },
+            UnixDatagram { socket: b, driver: self.driver.clone() },
+        ))
+    }
+}
+
+async fn send_fds<B: OwnedBuf>(driver: &Driver, fd: RawFd, buf: B, fds: Vec<RawFd>) -> (B, Result<usize>) {
+    let control = match Control::rights(&fds) {
+        Ok(control) => control,
+        Err(e) => { return (buf, Err(e)); }
+    };
+    let len = buf.as_ref().len();
+    let event = SendMsg { fd: Fd::Raw(fd), buf, len, parts: MsgParts::unnamed(control) };
+    let (event, wrote) = Submission::new(event, driver.clone()).await;
+    (event.buf, wrote.map(|wrote| wrote as usize))
+}
+
+async fn recv_fds<B: OwnedBuf>(driver: &Driver, fd: RawFd, buf: B, max: usize) -> (B, Result<(usize, Vec<fs::File>)>) {
+    let parts = MsgParts::unnamed(Control::with_room(max));
+    let event = RecvMsg { fd: Fd::Raw(fd), buf, parts, flags: libc::MSG_CMSG_CLOEXEC };
+    let (event, read) = Submission::new(event, driver.clone()).await;
+    let read = read.and_then(|read| Ok((read as usize, event.parts.received_fds()?)));
+    (event.buf, read)
+}
+
+impl AsRawFd for UnixListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+impl UnixListener {
+    pub async fn accept(&self) -> Result<UnixStream> {
+        let event = Accept { fd: Fd::Raw(self.socket.as_raw_fd()), addr: Box::new(SockAddr::empty()) };
+        let fd = Submission::new(event, self.driver.clone()).await.1?;
+        let socket = unsafe { net::UnixStream::from_raw_fd(fd as RawFd) };
+        Ok(UnixStream { socket, driver: self.driver.clone() })
+    }
+}
+
+impl AsRawFd for UnixStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+impl UnixStream {
+    pub fn shutdown(&self, how: Shutdown) -> Result<()> {
+        self.socket.shutdown(how)
+    }
+
+    pub async fn send<B: OwnedBuf>(&self, buf: B) -> (B, Result<usize>) {
+        let event = Send { fd: Fd::Raw(self.socket.as_raw_fd()), buf };
+        let (event, wrote) = Submission::new(event, self.driver.clone()).await;
+        (event.buf, wrote.map(|wrote| wrote as usize))
+    }
+
+    pub async fn recv<B: OwnedBuf>(&self, buf: B) -> (B, Result<usize>) {
+        let event = Recv { fd: Fd::Raw(self.socket.as_raw_fd()), buf };
+        let (event, read) = Submission::new(event, self.driver.clone()).await;
+        (event.buf, read.map(|read| read as usize))
+    }
+
+    pub async fn send_fds<B: OwnedBuf>(&self, buf: B, fds: Vec<RawFd>) -> (B, Result<usize>) {
+        send_fds(&self.driver, self.socket.as_raw_fd(), buf, fds).await
+    }
+
+    pub async fn recv_fds<B: OwnedBuf>(&self, buf: B, max: usize) -> (B, Result<(usize, Vec<fs::File>)>) {
+        recv_fds(&self.driver, self.socket.as_raw_fd(), buf, max).await
+    }
+}
+
+impl AsRawFd for UnixDatagram {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+impl UnixDatagram {
+    pub fn connect(&self, path: impl AsRef<Path>) -> Result<()> {
+        self.socket.connect(path)
+    }
+
+    pub async fn send<B: OwnedBuf>(&self, buf: B) -> (B, Result<usize>) {
+        let event = Send { fd: Fd::Raw(self.socket.as_raw_fd()), buf };
+        let (event, wrote) = Submission::new(event, self.driver.clone()).await;
+        (event.buf, wrote.map(|wrote| wrote as usize))
+    }
+
+    pub async fn recv<B: OwnedBuf>(&self, buf: B) -> (B, Result<usize>) {
+        let event = Recv { fd: Fd::Raw(self.socket.as_raw_fd()), buf };
+        let (event, read) = Submission::new(event, self.driver.clone()).await;
+        (event.buf, read.map(|read| read as usize))
+    }
+
+    pub async fn send_fds<B: OwnedBuf>(&self, buf: B, fds: Vec<RawFd>) -> (B, Result<usize>) {
+        send_fds(&self.driver, self.socket.as_raw_fd(), buf, fds).await
+    }
+
+    pub async fn recv_fds<B: OwnedBuf>(&self, buf: B, max: usize) -> (B, Result<(usize, Vec<fs::File>)>) {
+        recv_fds(&self.driver, self.socket.as_raw_fd(), buf, max).await
+    }
+}
@@ -0,0 +1,86 @@
+// Where live Buffers were made, for tracking down leaks. It takes a
+// backtrace per Buffer, so it's off unless asked for, and only covers
+// Buffers that took their first page while it's on. Buffers that never
+// hold a page, like the ones reads and writes leave behind, aren't worth
+// a backtrace.
+
+use once_cell::sync::Lazy;
+
+use std::backtrace::Backtrace;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+static RECORDING: AtomicBool = AtomicBool::new(false);
+static NEXT: AtomicU64 = AtomicU64::new(1);
+static SITES: Lazy<Mutex<HashMap<u64, Arc<Backtrace>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Buffers made at the same place, and how many of them are still live.
+#[derive(Debug)]
+pub struct BufferSite {
+    pub buffers: usize,
+    pub backtrace: String,
+}
+
+// A Buffer's entry in SITES, if it has one, which it leaves on drop.
+pub(crate) struct Site(Option<(u64, Arc<Backtrace>)>);
+
+impl Site {
+    pub(crate) fn none() -> Site {
+        Site(None)
+    }
+
+    pub(crate) fn is_none(&self) -> bool {
+        self.0.is_none()
+    }
+
+    pub(crate) fn new() -> Site {
+        if !RECORDING.load(Ordering::Relaxed) { return Site(None); }
+        Site::record(Arc::new(Backtrace::force_capture()))
+    }
+
+    // For a Buffer split off another one, which was made in the same place.
+    #[cfg(feature = "ringbahn")]
+    pub(crate) fn split(&self) -> Site {
+        match &self.0 {
+            Some((_, backtrace)) => Site::record(backtrace.clone()),
+            None => Site(None),
+        }
+    }
+
+    fn record(backtrace: Arc<Backtrace>) -> Site {
+        let id = NEXT.fetch_add(1, Ordering::Relaxed);
+        sites().insert(id, backtrace.clone());
+        Site(Some((id, backtrace)))
+    }
+}
+
+impl Drop for Site {
+    fn drop(&mut self) {
+        if let Some((id, _)) = self.0.take() {
+            sites().remove(&id);
+        }
+    }
+}
+
+fn sites() -> std::sync::MutexGuard<'static, HashMap<u64, Arc<Backtrace>>> {
+    SITES.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+pub(crate) fn set_recording(on: bool) {
+    RECORDING.store(on, Ordering::Relaxed);
+}
+
+// Most buffers first.
+pub(crate) fn live() -> Vec<BufferSite> {
+    let mut by_site: HashMap<String, usize> = HashMap::new();
+    for backtrace in sites().values() {
+        *by_site.entry(backtrace.to_string()).or_insert(0) += 1;
+    }
+    let mut live: Vec<BufferSite> = by_site.into_iter()
+        .map(|(backtrace, buffers)| BufferSite { buffers, backtrace })
+        .collect();
+    live.sort_by_key(|site| Reverse(site.buffers));
+    live
+}
@@ -0,0 +1,57 @@
+// splice(2), tee(2) and sendfile(2) for the threadpool. Linux only, and
+// blocking: callers run these under unblock.
+
+use std::io::{Error, ErrorKind};
+use std::os::unix::io::RawFd;
+use std::ptr;
+
+// Moves up to `len` bytes between two descriptors, at least one of which
+// must be a pipe. An offset of None uses (and advances) the descriptor's
+// own position, and must be None for the pipe end.
+pub(crate) fn splice(
+    from: RawFd, from_off: Option<u64>, to: RawFd, to_off: Option<u64>, len: usize
+) -> Result<usize, Error> {
+    let mut from_off = from_off.map(|off| off as libc::loff_t);
+    let mut to_off = to_off.map(|off| off as libc::loff_t);
+    loop {
+        let from_ptr = from_off.as_mut().map_or(ptr::null_mut(), |off| off as *mut _);
+        let to_ptr = to_off.as_mut().map_or(ptr::null_mut(), |off| off as *mut _);
+        let ret = unsafe { libc::splice(from, from_ptr, to, to_ptr, len, libc::SPLICE_F_MOVE) };
+        if ret != -1 { return Ok(ret as usize); }
+        let e = Error::last_os_error();
+        if e.kind() != ErrorKind::Interrupted { return Err(e); }
+    }
+}
+
+// Copies up to `len` bytes from one pipe to another without consuming them.
+pub(crate) fn tee(from: RawFd, to: RawFd, len: usize) -> Result<usize, Error> {
+    loop {
+        let ret = unsafe { libc::tee(from, to, len, 0) };
+        if ret != -1 { return Ok(ret as usize); }
+        let e = Error::last_os_error();
+        if e.kind() != ErrorKind::Interrupted { return Err(e); }
+    }
+}
+
+// Sends `len` bytes of the file at `offset` to `to`, stopping early at the
+// end of the file. Returns how much was sent; an error after some of it
+// went out just stops there.
+pub(crate) fn sendfile(to: RawFd, from: RawFd, offset: u64, len: u64) -> Result<u64, Error> {
+    let mut off = offset as libc::off_t;
+    let mut sent = 0;
+    while sent < len {
+        // Linux won't move more than this in one call anyway.
+        let chunk = (len - sent).min(0x7fff_f000) as usize;
+        let ret = unsafe { libc::sendfile(to, from, &mut off, chunk) };
+        match ret {
+            -1 => {
+                let e = Error::last_os_error();
+                if e.kind() == ErrorKind::Interrupted { continue; }
+                return if sent > 0 { Ok(sent) } else { Err(e) };
+            }
+            0 => break,
+            ret => { sent += ret as u64; }
+        }
+    }
+    Ok(sent)
+}
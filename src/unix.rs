@@ -0,0 +1,250 @@
+use crate::io::{File, OwnedBuf, IO};
+use crate::legacy;
+#[cfg(feature = "ringbahn")]
+use crate::ringbahn;
+
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::mem::{size_of, size_of_val, zeroed};
+use std::net::Shutdown;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::ptr;
+
+// The kernel won't pass more than this many descriptors in one message.
+const SCM_MAX_FD: usize = 253;
+
+pub enum UnixListener {
+    Legacy(legacy::UnixListener),
+    #[cfg(feature = "ringbahn")]
+    Ringbahn(ringbahn::UnixListener),
+}
+
+pub enum UnixStream {
+    Legacy(legacy::UnixStream),
+    #[cfg(feature = "ringbahn")]
+    Ringbahn(ringbahn::UnixStream),
+}
+
+pub enum UnixDatagram {
+    Legacy(legacy::UnixDatagram),
+    #[cfg(feature = "ringbahn")]
+    Ringbahn(ringbahn::UnixDatagram),
+}
+
+// Ancillary data for a sendmsg/recvmsg, for passing descriptors with
+// SCM_RIGHTS. Held in u64s to keep the cmsghdr aligned.
+pub(crate) struct Control {
+    buf: Vec<u64>,
+    len: usize,
+}
+
+impl Control {
+    pub(crate) fn none() -> Control {
+        Control { buf: Vec::new(), len: 0 }
+    }
+
+    // Room to receive up to `fds` descriptors.
+    pub(crate) fn with_room(fds: usize) -> Control {
+        if fds == 0 { return Control::none(); }
+        let len = unsafe { libc::CMSG_SPACE((fds * size_of::<RawFd>()) as u32) } as usize;
+        Control { buf: vec![0; len.div_ceil(8)], len }
+    }
+
+    pub(crate) fn rights(fds: &[RawFd]) -> Result<Control, Error> {
+        if fds.len() > SCM_MAX_FD {
+            return Err(Error::new(ErrorKind::InvalidInput, "can't pass more than 253 descriptors at once"));
+        }
+        let mut control = Control::with_room(fds.len());
+        if fds.is_empty() { return Ok(control); }
+        unsafe {
+            let mut msg: libc::msghdr = zeroed();
+            control.attach(&mut msg);
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(size_of_val(fds) as u32) as _;
+            ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg).cast::<RawFd>(), fds.len());
+        }
+        Ok(control)
+    }
+
+    pub(crate) fn attach(&mut self, msg: &mut libc::msghdr) {
+        if self.len == 0 {
+            msg.msg_control = ptr::null_mut();
+            msg.msg_controllen = 0;
+        } else {
+            msg.msg_control = self.buf.as_mut_ptr().cast();
+            msg.msg_controllen = self.len as _;
+        }
+    }
+}
+
+// Takes ownership of the descriptors a recvmsg delivered into the control
+// buffer attached to `msg`. Closes them all again if the kernel had to
+// drop some for lack of room, since a partial set is no use to anyone.
+pub(crate) unsafe fn received_fds(msg: &libc::msghdr) -> Result<Vec<fs::File>, Error> {
+    let mut files = Vec::new();
+    if msg.msg_control.is_null() { return Ok(files); }
+    let mut cmsg = libc::CMSG_FIRSTHDR(msg);
+    while !cmsg.is_null() {
+        if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+            let data = libc::CMSG_DATA(cmsg).cast::<RawFd>();
+            let count = ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize) / size_of::<RawFd>();
+            for i in 0..count {
+                files.push(fs::File::from_raw_fd(ptr::read_unaligned(data.add(i))));
+            }
+        }
+        cmsg = libc::CMSG_NXTHDR(msg, cmsg);
+    }
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        return Err(Error::new(ErrorKind::InvalidData, "more descriptors were sent than there was room for"));
+    }
+    Ok(files)
+}
+
+fn raw_fds(files: &[&File]) -> Vec<RawFd> {
+    files.iter().map(|file| file.as_raw_fd()).collect()
+}
+
+// Received descriptors become Files on the receiving IO, whatever backend
+// the socket itself is using.
+fn adopt<B>(io: &IO, (buf, res): (B, Result<(usize, Vec<fs::File>), Error>)) -> (B, Result<(usize, Vec<File>), Error>) {
+    (buf, res.map(|(len, files)| (len, files.into_iter().map(|file| io.from_file(file)).collect())))
+}
+
+impl AsRawFd for UnixListener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            UnixListener::Legacy(listener) => listener.as_raw_fd(),
+            #[cfg(feature = "ringbahn")]
+            UnixListener::Ringbahn(listener) => listener.as_raw_fd(),
+        }
+    }
+}
+
+impl UnixListener {
+    pub async fn accept(&self) -> Result<UnixStream, Error> {
+        match self {
+            UnixListener::Legacy(listener) => Ok(UnixStream::Legacy(listener.accept().await?)),
+            #[cfg(feature = "ringbahn")]
+            UnixListener::Ringbahn(listener) => Ok(UnixStream::Ringbahn(listener.accept().await?)),
+        }
+    }
+}
+
+impl AsRawFd for UnixStream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            UnixStream::Legacy(stream) => stream.as_raw_fd(),
+            #[cfg(feature = "ringbahn")]
+            UnixStream::Ringbahn(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+impl UnixStream {
+    pub fn shutdown(&self, how: Shutdown) -> Result<(), Error> {
+        match self {
+            UnixStream::Legacy(stream) => stream.shutdown(how),
+            #[cfg(feature = "ringbahn")]
+            UnixStream::Ringbahn(stream) => stream.shutdown(how),
+        }
+    }
+
+    pub async fn send<B: OwnedBuf>(&self, buf: B) -> (B, Result<usize, Error>) {
+        match self {
+            UnixStream::Legacy(stream) => stream.send(buf).await,
+            #[cfg(feature = "ringbahn")]
+            UnixStream::Ringbahn(stream) => stream.send(buf).await,
+        }
+    }
+
+    pub async fn recv<B: OwnedBuf>(&self, buf: B) -> (B, Result<usize, Error>) {
+        match self {
+            UnixStream::Legacy(stream) => stream.recv(buf).await,
+            #[cfg(feature = "ringbahn")]
+            UnixStream::Ringbahn(stream) => stream.recv(buf).await,
+        }
+    }
+
+    // Sends `buf` with `files` attached. The descriptors are duplicated
+    // into the receiver, so the files stay open here too. `buf` must not
+    // be empty: the descriptors ride along with the data.
+    pub async fn send_files<B: OwnedBuf>(&self, buf: B, files: &[&File]) -> (B, Result<usize, Error>) {
+        let fds = raw_fds(files);
+        match self {
+            UnixStream::Legacy(stream) => stream.send_fds(buf, fds).await,
+            #[cfg(feature = "ringbahn")]
+            UnixStream::Ringbahn(stream) => stream.send_fds(buf, fds).await,
+        }
+    }
+
+    // Receives into `buf` along with up to `max` files, which come back
+    // bound to `io`.
+    pub async fn recv_files<B: OwnedBuf>(
+        &self, io: &IO, buf: B, max: usize
+    ) -> (B, Result<(usize, Vec<File>), Error>) {
+        match self {
+            UnixStream::Legacy(stream) => adopt(io, stream.recv_fds(buf, max).await),
+            #[cfg(feature = "ringbahn")]
+            UnixStream::Ringbahn(stream) => adopt(io, stream.recv_fds(buf, max).await),
+        }
+    }
+}
+
+impl AsRawFd for UnixDatagram {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            UnixDatagram::Legacy(socket) => socket.as_raw_fd(),
+            #[cfg(feature = "ringbahn")]
+            UnixDatagram::Ringbahn(socket) => socket.as_raw_fd(),
+        }
+    }
+}
+
+impl UnixDatagram {
+    // Sets the default destination for send; connecting a datagram
+    // socket doesn't block.
+    pub fn connect(&self, path: impl AsRef<std::path::Path>) -> Result<(), Error> {
+        match self {
+            UnixDatagram::Legacy(socket) => socket.connect(path),
+            #[cfg(feature = "ringbahn")]
+            UnixDatagram::Ringbahn(socket) => socket.connect(path),
+        }
+    }
+
+    pub async fn send<B: OwnedBuf>(&self, buf: B) -> (B, Result<usize, Error>) {
+        match self {
+            UnixDatagram::Legacy(socket) => socket.send(buf).await,
+            #[cfg(feature = "ringbahn")]
+            UnixDatagram::Ringbahn(socket) => socket.send(buf).await,
+        }
+    }
+
+    pub async fn recv<B: OwnedBuf>(&self, buf: B) -> (B, Result<usize, Error>) {
+        match self {
+            UnixDatagram::Legacy(socket) => socket.recv(buf).await,
+            #[cfg(feature = "ringbahn")]
+            UnixDatagram::Ringbahn(socket) => socket.recv(buf).await,
+        }
+    }
+
+    pub async fn send_files<B: OwnedBuf>(&self, buf: B, files: &[&File]) -> (B, Result<usize, Error>) {
+        let fds = raw_fds(files);
+        match self {
+            UnixDatagram::Legacy(socket) => socket.send_fds(buf, fds).await,
+            #[cfg(feature = "ringbahn")]
+            UnixDatagram::Ringbahn(socket) => socket.send_fds(buf, fds).await,
+        }
+    }
+
+    pub async fn recv_files<B: OwnedBuf>(
+        &self, io: &IO, buf: B, max: usize
+    ) -> (B, Result<(usize, Vec<File>), Error>) {
+        match self {
+            UnixDatagram::Legacy(socket) => adopt(io, socket.recv_fds(buf, max).await),
+            #[cfg(feature = "ringbahn")]
+            UnixDatagram::Ringbahn(socket) => adopt(io, socket.recv_fds(buf, max).await),
+        }
+    }
+}